@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::env;
+
+/// Replace `${VAR}` in s with the value of the environment variable `VAR`
+///
+/// `$$` is a literal `$`, a `$` not followed by `{` or `$` is kept as is.
+/// Unset variables and unterminated `${` are errors.
+pub fn expand_env(s: &str) -> Result<String, String> {
+    let mut expanded = String::with_capacity(s.len());
+    let mut rest = s;
+    while let Some(i) = rest.find('$') {
+        expanded.push_str(&rest[..i]);
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            expanded.push('$');
+            rest = after;
+        } else if let Some(after) = after.strip_prefix('{') {
+            let end = after.find('}').ok_or_else(|| {
+                format!("Unterminated `${{` in: {}", s)
+            })?;
+            let var = &after[..end];
+            if var.is_empty() {
+                return Err(format!("Empty variable name `${{}}` in: {}", s));
+            }
+            let value = env::var(var).map_err(|e| {
+                format!("Unable to expand `${{{}}}` in: {} ({})", var, s, e)
+            })?;
+            expanded.push_str(&value);
+            rest = &after[end + 1..];
+        } else {
+            expanded.push('$');
+            rest = after;
+        }
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
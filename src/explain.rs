@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fmt;
+
+use provider::{MirrorError, Provider};
+use {is_allowed_destination, MirrorOptions};
+
+/// Whether a run would sync a project
+#[derive(Debug, Clone, PartialEq)]
+pub enum Decision {
+    Included,
+    /// Not synced for the given reason, e.g. `skip flag`
+    Skipped(String),
+    /// Refused for the given reason, the run reports the mirror as blocked
+    Blocked(String),
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Decision::Included => write!(f, "included"),
+            Decision::Skipped(ref reason) => write!(f, "skipped: {}", reason),
+            Decision::Blocked(ref reason) => write!(f, "blocked: {}", reason),
+        }
+    }
+}
+
+/// The decision for a project, or for a single destination of a mirror
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explanation {
+    /// `<origin> -> <destination>` for mirrors, the url of the project otherwise
+    pub subject: String,
+    pub decision: Decision,
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.subject, self.decision)
+    }
+}
+
+/// Decide for every project of provider whether a run with opts would sync it, without running git
+///
+/// The filters are applied in the order of a run: those of the provider, the description, the labels,
+/// the rewrites, the failure cache and the allowed destination hosts. Submodules and decisions made
+/// while syncing, e.g. by `changed_only` or the host failure budget, aren't known beforehand.
+pub fn explain(provider: &dyn Provider, opts: &MirrorOptions) -> Result<Vec<Explanation>, String> {
+    let (mirrors, filtered) = provider.get_mirror_repos_explained()?;
+    let mut explanations: Vec<Explanation> = filtered
+        .into_iter()
+        .map(|p| {
+            Explanation {
+                subject: p.web_url,
+                decision: Decision::Skipped(p.reason),
+            }
+        })
+        .collect();
+    for (m, _) in mirrors {
+        let m = match m {
+            Ok(m) => m,
+            Err(MirrorError::Skip(url)) => {
+                explanations.push(Explanation {
+                    subject: url,
+                    decision: Decision::Skipped("skip flag".to_owned()),
+                });
+                continue;
+            }
+            Err(MirrorError::Description(url, e)) => {
+                explanations.push(Explanation {
+                    subject: url,
+                    decision: Decision::Skipped(format!("invalid description ({})", e)),
+                });
+                continue;
+            }
+        };
+        if let Some(ref labels) = opts.labels {
+            if !m.labels.iter().any(|l| labels.contains(l)) {
+                explanations.push(Explanation {
+                    subject: format!("{} -> {}", m.origin, m.destination),
+                    decision: Decision::Skipped(format!("label filter (labels: {})", m.labels.join(", "))),
+                });
+                continue;
+            }
+        }
+        let m = opts.rewrites.apply(m);
+        for destination in m.destinations() {
+            let recent_failure = match opts.failure_cache {
+                Some(ref cache) if !opts.retry_failed => cache.recent_failure(&m.origin, destination),
+                _ => None,
+            };
+            let decision = if let Some(time) = recent_failure {
+                Decision::Skipped(format!("recent failure at {}", time))
+            } else if !is_allowed_destination(destination, &opts.allowed_destination_hosts) {
+                Decision::Blocked("destination host not allowed".to_owned())
+            } else {
+                Decision::Included
+            };
+            explanations.push(Explanation {
+                subject: format!("{} -> {}", m.origin, destination),
+                decision,
+            });
+        }
+    }
+    Ok(explanations)
+}
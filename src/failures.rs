@@ -0,0 +1,98 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+
+// Used to store the cache
+extern crate serde_json;
+
+use {SyncResult, SyncStatus};
+
+/// Mirrors that failed recently, stored in a JSON file between runs
+///
+/// A mirror that failed less than cooldown ago is skipped, so origins that are gone aren't tried every run.
+#[derive(Debug, Clone)]
+pub struct FailureCache {
+    path: PathBuf,
+    pub cooldown: Duration,
+    /// Unix time of the last failure by `<origin> -> <destination>`
+    failures: HashMap<String, i64>,
+}
+
+fn key(origin: &str, destination: &str) -> String {
+    format!("{} -> {}", origin, destination)
+}
+
+impl FailureCache {
+    /// Load the cache from path, a missing or unreadable file results in an empty cache
+    pub fn load(path: PathBuf, cooldown: Duration) -> FailureCache {
+        let failures = match File::open(&path) {
+            Ok(f) => {
+                serde_json::from_reader(f).unwrap_or_else(|e| {
+                    warn!("Ignoring invalid failure cache {:?} ({})", path, e);
+                    HashMap::new()
+                })
+            }
+            Err(e) => {
+                debug!("No failure cache at {:?} ({})", path, e);
+                HashMap::new()
+            }
+        };
+        FailureCache {
+            path,
+            cooldown,
+            failures,
+        }
+    }
+
+    fn is_recent(&self, time: i64, now: DateTime<Utc>) -> bool {
+        now.timestamp() - time < self.cooldown.as_secs() as i64
+    }
+
+    /// When origin last failed to sync to destination, if that is less than the cooldown ago
+    pub fn recent_failure(&self, origin: &str, destination: &str) -> Option<DateTime<Utc>> {
+        let time = *self.failures.get(&key(origin, destination))?;
+        if self.is_recent(time, Utc::now()) {
+            Some(Utc.timestamp(time, 0))
+        } else {
+            None
+        }
+    }
+
+    /// Write the cache updated with the results of a run
+    ///
+    /// Failed mirrors are recorded with the current time, synced ones are removed, as are failures
+    /// older than the cooldown. Mirrors skipped because of a recent failure keep their time.
+    pub fn save(&self, results: &[SyncResult]) -> Result<(), String> {
+        let now = Utc::now();
+        let mut failures = self.failures.clone();
+        for result in results {
+            let key = key(&result.origin, &result.destination);
+            match result.status {
+                SyncStatus::Failed(_) => {
+                    failures.insert(key, now.timestamp());
+                }
+                SyncStatus::Ok | SyncStatus::UpToDate => {
+                    failures.remove(&key);
+                }
+                SyncStatus::Skipped(_) | SyncStatus::Blocked(_) => {}
+            }
+        }
+        failures.retain(|_, &mut time| self.is_recent(time, now));
+
+        let file = File::create(&self.path).map_err(|e| {
+            format!("Unable to write failure cache {:?} ({})", self.path, e)
+        })?;
+        serde_json::to_writer(file, &failures).map_err(|e| {
+            format!("Unable to write failure cache {:?} ({})", self.path, e)
+        })
+    }
+}
@@ -0,0 +1,1926 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::process::{Command, Stdio};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use std::thread;
+use std::io::Read;
+use std::fmt;
+use std::collections::BTreeMap;
+use std::io::Write;
+
+// Used to kill the whole process group of a git command
+#[cfg(unix)]
+extern crate libc;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+
+use log::LogLevel::{Debug, Info};
+
+// Time handling
+use chrono::Local;
+
+use {Limits, NamingScheme, OnConflict};
+use probe::{is_http, probe_refs};
+use provider::Credentials;
+
+use slug::slugify;
+
+// Interval in which running git commands are checked for completion
+const POLL_INTERVAL_MS: u64 = 100;
+
+// Minimal interval between two logged progress messages of a git command
+const PROGRESS_INTERVAL_MS: u64 = 1000;
+
+// Directory inside the mirror directory used for the temporary clones of smoke tests
+const SMOKE_DIR: &str = ".smoke";
+
+// Refspecs pushed unless all refs are mirrored, this excludes e.g. GitLab's
+// refs/merge-requests/* and refs/pipelines/*
+const BRANCHES_AND_TAGS: [&str; 2] = ["+refs/heads/*:refs/heads/*", "+refs/tags/*:refs/tags/*"];
+
+// First git version supporting negative refspecs (`^refs/heads/x`), used by `branch_map_refspecs`
+const NEGATIVE_REFSPECS_VERSION: (u32, u32) = (2, 29);
+
+// Directory inside the mirror directory holding the credentials files of running origin fetches
+const CREDENTIALS_DIR: &str = ".credentials";
+
+// Environment variable naming the credentials file of an origin fetch
+const CREDENTIALS_ENV: &str = "GIT_MIRROR_CREDENTIALS";
+
+// Credential helper answering git's `get` requests with the contents of the credentials file.
+// Only the path is passed in the environment, so the secret doesn't show up in logged commands.
+const CREDENTIAL_HELPER: &str = "!f() { test \"$1\" = get && cat \"$GIT_MIRROR_CREDENTIALS\"; }; f";
+
+/// Settings shared by all git commands of a mirror
+#[derive(Debug, Clone, Default)]
+pub struct Git {
+    /// Git commands still running at the deadline are killed
+    pub deadline: Option<Instant>,
+    /// Don't use credentials from credential helpers, askpass programs or the system config
+    pub isolate_credentials: bool,
+    /// How the local directories of origins are named
+    pub naming_scheme: NamingScheme,
+    /// Try to fetch the missing history if the local mirror of a shallow origin is shallow
+    pub unshallow: bool,
+    /// Limits a local mirror has to stay within to be pushed
+    pub limits: Limits,
+    /// Don't remove lock files left behind by interrupted git commands
+    pub keep_locks: bool,
+    /// HTTP basic auth credentials used for the origin
+    pub origin_credentials: Option<Credentials>,
+    /// Git config passed to every git command with `-c`, see `validate_config`
+    pub config: Vec<(String, String)>,
+    /// Number of loose objects above which the local mirror is garbage collected, see `maintain`
+    pub gc_threshold: Option<u64>,
+    /// Extra arguments of the clone and fetch of the origin
+    pub clone_args: ExtraArgs,
+    /// Extra arguments of the pushes to the destinations
+    pub push_args: ExtraArgs,
+    /// List the refs of HTTP(S) origins with a single request instead of `git ls-remote`, see `probe_refs`
+    pub freshness_probe: bool,
+    /// The mirror renames branches, which needs negative refspecs, see `branch_map_refspecs`
+    pub renames_branches: bool,
+}
+
+/// Credentials of an origin in git credential format, in a file only the current user can read
+///
+/// The file is removed when it is dropped.
+struct CredentialsFile {
+    path: PathBuf,
+}
+
+impl CredentialsFile {
+    fn create(path: PathBuf, credentials: &Credentials) -> Result<CredentialsFile, String> {
+        let mut content = String::new();
+        for (key, value) in [
+            ("username", &credentials.username),
+            ("password", &credentials.password),
+        ]
+        {
+            if let Some(ref value) = *value {
+                if value.contains('\n') || value.contains('\0') {
+                    return Err(format!("Invalid origin_{}: contains a newline or NUL", key));
+                }
+                content.push_str(&format!("{}={}\n", key, value));
+            }
+        }
+
+        // Don't reuse a file left behind with other permissions
+        let _ = fs::remove_file(&path);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let file = CredentialsFile { path };
+        options
+            .open(&file.path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .map_err(|e| {
+                format!("Unable to write credentials file {:?} ({})", file.path, e)
+            })?;
+        Ok(file)
+    }
+}
+
+impl Drop for CredentialsFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Unable to remove credentials file {:?} ({})", self.path, e);
+        }
+    }
+}
+
+/// A failed git command
+#[derive(Debug)]
+struct CmdError {
+    message: String,
+    /// Everything the command wrote to stderr
+    stderr: String,
+}
+
+impl From<CmdError> for String {
+    fn from(e: CmdError) -> String {
+        e.message
+    }
+}
+
+/// Run a git command interacting with remote, killing it if it is still running at the deadline
+///
+/// The stderr of the command is passed through and also returned on failure,
+/// progress messages are logged instead, at most once per second.
+/// Returns the stdout of the command if it is piped, otherwise an empty string.
+fn run_cmd(
+    cmd: &mut Command,
+    name: &str,
+    remote: &str,
+    deadline: Option<Instant>,
+) -> Result<String, CmdError> {
+    trace!("{} command started: {:?}", name, cmd);
+
+    let error = |message: String, stderr: String| CmdError { message, stderr };
+
+    cmd.stderr(Stdio::piped());
+    let mut child = cmd.spawn().map_err(|e| {
+        error(
+            format!(
+                "Unable to execute {} command: {:?} ({})",
+                name.to_lowercase(),
+                cmd,
+                e
+            ),
+            String::new(),
+        )
+    })?;
+
+    // Forward stderr while collecting it
+    let stderr = child.stderr.take().expect("stderr is piped");
+    let remote = remote.to_owned();
+    let reader = thread::spawn(move || read_stderr(stderr, &remote));
+    let stdout_reader = child.stdout.take().map(|mut stdout| {
+        thread::spawn(move || {
+            let mut out = String::new();
+            stdout.read_to_string(&mut out).map(|_| out).unwrap_or_default()
+        })
+    });
+
+    let status = loop {
+        let status = child.try_wait().map_err(|e| {
+            error(
+                format!("Unable to wait for {} command: {:?} ({})", name.to_lowercase(), cmd, e),
+                String::new(),
+            )
+        })?;
+        if let Some(status) = status {
+            break status;
+        }
+        if let Some(deadline) = deadline {
+            if Instant::now() >= deadline {
+                kill(&mut child);
+                return Err(error(
+                    format!("Timeout: {} command ({:?}) was killed", name, cmd),
+                    String::new(),
+                ));
+            }
+        }
+        thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+    };
+
+    let stderr = reader.join().unwrap_or_default();
+    let stdout = stdout_reader
+        .map(|r| r.join().unwrap_or_default())
+        .unwrap_or_default();
+
+    if !status.success() {
+        return Err(error(
+            format!(
+                "{} command ({:?}) failed with exit code: {}",
+                name,
+                cmd,
+                status
+            ),
+            stderr,
+        ));
+    }
+
+    Ok(stdout)
+}
+
+/// Run a local git command and return its stdout
+fn output(cmd: &mut Command, name: &str) -> Result<String, String> {
+    trace!("{} command started: {:?}", name, cmd);
+    let out = cmd.stdout(Stdio::piped()).output().map_err(|e| {
+        format!("Unable to execute {} command: {:?} ({})", name.to_lowercase(), cmd, e)
+    })?;
+    if !out.status.success() {
+        return Err(format!(
+            "{} command ({:?}) failed with exit code: {} ({})",
+            name,
+            cmd,
+            out.status,
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+}
+
+/// Read the stderr of a git command, returning everything except progress messages
+fn read_stderr<R: Read>(mut stderr: R, remote: &str) -> String {
+    let mut output = String::new();
+    let mut last_progress: Option<(Instant, String, u8)> = None;
+
+    let mut handle_line = |line: &[u8]| {
+        let line = String::from_utf8_lossy(line);
+        match parse_progress(&line) {
+            Some(progress) => {
+                let now = Instant::now();
+                let emit = match last_progress {
+                    None => true,
+                    // Git repeats the final message of a phase with ", done."
+                    Some((_, ref phase, percent))
+                        if *phase == progress.phase && percent == progress.percent => false,
+                    Some((t, ref phase, _)) => {
+                        *phase != progress.phase || progress.percent == 100 ||
+                            now.duration_since(t) >= Duration::from_millis(PROGRESS_INTERVAL_MS)
+                    }
+                };
+                if emit {
+                    info!("{}: {}", remote, progress);
+                    last_progress = Some((now, progress.phase, progress.percent));
+                }
+            }
+            None => {
+                eprintln!("{}", line);
+                output.push_str(&line);
+                output.push('\n');
+            }
+        }
+    };
+
+    // Progress messages are terminated by \r instead of \n
+    let mut line = Vec::new();
+    let mut buf = [0; 4096];
+    while let Ok(n) = stderr.read(&mut buf) {
+        if n == 0 {
+            break;
+        }
+        for &b in &buf[..n] {
+            if b == b'\r' || b == b'\n' {
+                if !line.is_empty() {
+                    handle_line(&line);
+                    line.clear();
+                }
+            } else {
+                line.push(b);
+            }
+        }
+    }
+    if !line.is_empty() {
+        handle_line(&line);
+    }
+
+    output
+}
+
+/// A progress message of git, e.g. `Receiving objects:  45% (450/1000), 1.20 MiB | 1.00 MiB/s`
+#[derive(Debug, PartialEq)]
+struct Progress {
+    phase: String,
+    percent: u8,
+    done: u64,
+    total: u64,
+}
+
+impl fmt::Display for Progress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {}/{} objects ({}%)",
+            self.phase.to_lowercase(),
+            self.done,
+            self.total,
+            self.percent
+        )
+    }
+}
+
+fn parse_progress(line: &str) -> Option<Progress> {
+    let line = line.trim_start_matches("remote: ");
+    let colon = line.find(':')?;
+    let (phase, rest) = (&line[..colon], &line[colon + 1..]);
+    let percent = rest.trim_start().split('%').next()?.trim().parse().ok()?;
+    let counts = rest.split('(').nth(1)?.split(')').next()?;
+    let mut counts = counts.split('/');
+    let done = counts.next()?.parse().ok()?;
+    let total = counts.next()?.parse().ok()?;
+    Some(Progress {
+        phase: phase.trim().to_owned(),
+        percent,
+        done,
+        total,
+    })
+}
+
+/// Kill a git command and all processes it started
+fn kill(child: &mut ::std::process::Child) {
+    warn!("Killing git command with pid {}", child.id());
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(child.id() as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = child.kill();
+    // Reap the killed process
+    let _ = child.wait();
+}
+
+/// The refs pushed to a destination
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refs<'a> {
+    /// All refs, like `git push --mirror`
+    All,
+    /// Only `refs/heads/*` and `refs/tags/*`, deleting the ones missing in the origin
+    BranchesAndTags,
+    /// The given refspecs, passed to `git push` as is
+    Custom(&'a [String]),
+}
+
+/// Check the syntax of refspecs passed to `git push`, e.g. `+refs/heads/*:refs/heads/*`
+pub fn validate_refspecs(refspecs: &[String]) -> Result<(), String> {
+    if refspecs.is_empty() {
+        return Err("Invalid refspecs: no refspec given".to_owned());
+    }
+    for spec in refspecs {
+        validate_refspec(spec).map_err(
+            |e| format!("Invalid refspec `{}`: {}", spec, e),
+        )?;
+    }
+    Ok(())
+}
+
+fn validate_refspec(spec: &str) -> Result<(), String> {
+    let (negative, spec) = match spec.strip_prefix('^') {
+        Some(s) => (true, s),
+        None => (false, spec.strip_prefix('+').unwrap_or(spec)),
+    };
+    let mut sides = spec.split(':');
+    let src = sides.next().unwrap_or("");
+    let dst = sides.next();
+    if sides.next().is_some() {
+        return Err("more than one `:`".to_owned());
+    }
+    if negative && dst.is_some() {
+        return Err("a negative refspec can't have a destination".to_owned());
+    }
+    if src.is_empty() && dst.unwrap_or("").is_empty() {
+        return Err("neither source nor destination given".to_owned());
+    }
+    if let Some(dst) = dst {
+        if src.contains('*') != dst.contains('*') && !src.is_empty() && !dst.is_empty() {
+            return Err("either both or none of source and destination must contain `*`".to_owned());
+        }
+    }
+    for side in Some(src).into_iter().chain(dst).filter(|s| !s.is_empty()) {
+        validate_ref_pattern(side)?;
+    }
+    Ok(())
+}
+
+/// Check a ref name pattern according to the rules of `git check-ref-format`
+fn validate_ref_pattern(name: &str) -> Result<(), String> {
+    if name.matches('*').count() > 1 {
+        return Err(format!("`{}` contains more than one `*`", name));
+    }
+    if let Some(c) = name.chars().find(|c| {
+        c.is_whitespace() || c.is_control() || "~^:?[\\".contains(*c)
+    })
+    {
+        return Err(format!("`{}` contains the invalid character {:?}", name, c));
+    }
+    if name.contains("..") || name.contains("@{") || name.contains("//") {
+        return Err(format!("`{}` contains `..`, `@{{` or `//`", name));
+    }
+    if name.starts_with('/') || name.ends_with('/') || name.ends_with('.') {
+        return Err(format!("`{}` starts or ends with `/` or ends with `.`", name));
+    }
+    if name.split('/').any(|c| c.starts_with('.') || c.ends_with(".lock")) {
+        return Err(format!(
+            "`{}` has a component starting with `.` or ending with `.lock`",
+            name
+        ));
+    }
+    Ok(())
+}
+
+/// Refspecs pushing the branches of branch_map (by their name in the origin) to their new name
+///
+/// All other refs, or only the other branches and tags without all_refs, are pushed unchanged. A renamed
+/// branch replaces an origin branch with its new name, git prefers explicit refspecs over patterns. The old
+/// names are excluded with negative refspecs, which need git 2.29, unless another branch is renamed to them.
+pub fn branch_map_refspecs(branch_map: &BTreeMap<String, String>, all_refs: bool) -> Vec<String> {
+    let mut refspecs: Vec<String> = if all_refs {
+        vec!["+refs/*:refs/*".to_owned()]
+    } else {
+        BRANCHES_AND_TAGS.iter().map(|s| s.to_string()).collect()
+    };
+    for (from, to) in branch_map {
+        // Negative push refspecs match destinations, so the old name is only excluded if it isn't the
+        // new name of another branch, that branch would be excluded as well
+        if !branch_map.values().any(|v| v == from) {
+            refspecs.push(format!("^refs/heads/{}", from));
+        }
+        refspecs.push(format!("+refs/heads/{}:refs/heads/{}", from, to));
+    }
+    refspecs
+}
+
+/// The refs a destination should have after pushing the given origin refs, see `Refs`
+///
+/// Custom refspecs are applied like `git push` does: a `*` in the source matches the rest of a
+/// name, a source without `refs/` is looked up in the branches and tags, negative refspecs exclude the
+/// destinations they match, refspecs without `*` take precedence over patterns pushing to the same
+/// destination and deleting refspecs (`:<dst>`) are ignored.
+pub fn pushed_refs(origin_refs: &BTreeMap<String, String>, refs: Refs) -> BTreeMap<String, String> {
+    let refspecs = match refs {
+        Refs::All => return origin_refs.clone(),
+        Refs::BranchesAndTags => {
+            return origin_refs
+                .iter()
+                .filter(|(name, _)| name.starts_with("refs/heads/") || name.starts_with("refs/tags/"))
+                .map(|(name, id)| (name.clone(), id.clone()))
+                .collect()
+        }
+        Refs::Custom(refspecs) => refspecs,
+    };
+    let excluded: Vec<&str> = refspecs.iter().filter_map(|s| s.strip_prefix('^')).collect();
+    let (patterns, explicit): (Vec<&String>, Vec<&String>) = refspecs
+        .iter()
+        .filter(|s| !s.starts_with('^'))
+        .partition(|s| s.contains('*'));
+    let mut pushed = BTreeMap::new();
+    // Explicit refspecs are applied last, so they replace the refs of patterns
+    for spec in patterns.into_iter().chain(explicit) {
+        let spec = spec.strip_prefix('+').unwrap_or(spec);
+        let (src, dst) = match spec.split_once(':') {
+            Some((src, dst)) => (src, dst),
+            None => (spec, spec),
+        };
+        if src.is_empty() {
+            continue;
+        }
+        for (name, id) in origin_refs {
+            if let Some(matched) = match_ref(src, name) {
+                let dst = if dst.is_empty() { name.as_str() } else { dst };
+                let dst = if dst.contains('*') {
+                    dst.replacen('*', matched, 1)
+                } else if dst.starts_with("refs/") {
+                    dst.to_owned()
+                } else if name.starts_with("refs/tags/") {
+                    format!("refs/tags/{}", dst)
+                } else {
+                    // A short destination is of the same kind as the source, e.g. a branch
+                    format!("refs/heads/{}", dst)
+                };
+                if excluded.iter().any(|&e| match_ref(e, &dst).is_some()) {
+                    continue;
+                }
+                pushed.insert(dst, id.clone());
+            }
+        }
+    }
+    pushed
+}
+
+/// The part of name matched by the `*` of pattern, an empty string if pattern matches without `*`
+fn match_ref<'a>(pattern: &str, name: &'a str) -> Option<&'a str> {
+    if let Some((prefix, suffix)) = pattern.split_once('*') {
+        if name.len() >= prefix.len() + suffix.len() && name.starts_with(prefix) && name.ends_with(suffix) {
+            return Some(&name[prefix.len()..name.len() - suffix.len()]);
+        }
+        return None;
+    }
+    let matches = if pattern.starts_with("refs/") {
+        name == pattern
+    } else {
+        name.strip_prefix("refs/heads/").or_else(|| name.strip_prefix("refs/tags/")) == Some(pattern)
+    };
+    if matches { Some("") } else { None }
+}
+
+// Sections of git config keys a mirror description may set. Keys that make git run programs or read
+// credentials (e.g. `core.sshCommand`, `credential.helper`, `remote.*.uploadpack`) are not allowed,
+// descriptions are often editable by more people than the machine running the mirror.
+const CONFIG_SECTIONS: [&str; 6] = ["fetch", "gc", "pack", "push", "repack", "transfer"];
+
+// Keys of the core section a mirror description may set
+const CORE_CONFIG_KEYS: [&str; 5] = [
+    "bigfilethreshold",
+    "compression",
+    "deltabasecachelimit",
+    "packedgitlimit",
+    "packedgitwindowsize",
+];
+
+// Keys of the http section a mirror description may set. Others like `http.proxy`, `http.sslVerify` or
+// `http.sslCert` could send the origin credentials elsewhere or use client certificates of the machine,
+// so neither they nor `http.<url>.*` subsections are allowed.
+const HTTP_CONFIG_KEYS: [&str; 5] = ["postbuffer", "lowspeedlimit", "lowspeedtime", "version", "maxrequests"];
+
+/// Check the git config of a mirror, keys have the form `section.key` or `section.subsection.key`
+pub fn validate_config(config: &[(String, String)]) -> Result<(), String> {
+    for (key, value) in config {
+        validate_config_key(key).map_err(|e| {
+            format!("Invalid git_config key `{}`: {}", key, e)
+        })?;
+        if value.contains('\n') || value.contains('\0') {
+            return Err(format!("Invalid git_config value of `{}`: contains a newline or NUL", key));
+        }
+    }
+    Ok(())
+}
+
+fn validate_config_key(key: &str) -> Result<(), String> {
+    let (section, rest) = key.split_once('.').ok_or("expected `section.key`")?;
+    // The subsection may contain dots, e.g. `fetch.https://example.org/.key`
+    let name = rest.rsplit('.').next().unwrap_or(rest);
+    let is_name = |s: &str, first_alpha: bool| {
+        !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') &&
+            (!first_alpha || s.starts_with(|c: char| c.is_ascii_alphabetic()))
+    };
+    if !is_name(section, false) {
+        return Err("the section may only contain letters, digits and `-`".to_owned());
+    }
+    if !is_name(name, true) {
+        return Err("the key has to start with a letter and may only contain letters, digits and `-`".to_owned());
+    }
+    if key.contains(['=', '\n', '\0']) {
+        return Err("contains `=`, a newline or NUL".to_owned());
+    }
+    let section = section.to_ascii_lowercase();
+    let allowed = match section.as_str() {
+        "core" => rest == name && CORE_CONFIG_KEYS.contains(&name.to_ascii_lowercase().as_str()),
+        "http" => rest == name && HTTP_CONFIG_KEYS.contains(&name.to_ascii_lowercase().as_str()),
+        section => CONFIG_SECTIONS.contains(&section),
+    };
+    if !allowed {
+        return Err(format!(
+            "not allowed, only keys of the sections {}, core.{{{}}} and http.{{{}}} can be set",
+            CONFIG_SECTIONS.join(", "),
+            CORE_CONFIG_KEYS.join(","),
+            HTTP_CONFIG_KEYS.join(",")
+        ));
+    }
+    Ok(())
+}
+
+// Options that may be added to the clone and fetch of an origin, those ending with `=` need a value
+const FETCH_OPTIONS: [&str; 6] = ["--no-tags", "--ipv4", "--ipv6", "--jobs=", "--server-option=", "--verbose"];
+
+// Options that may be added to a push, those ending with `=` need a value
+const PUSH_OPTIONS: [&str; 6] = ["--atomic", "--no-verify", "--no-thin", "--ipv4", "--ipv6", "--push-option="];
+
+/// Extra arguments for the git commands of a mirror, split into config and options
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExtraArgs {
+    /// Given as `-c <key>=<value>`, passed before the subcommand, see `validate_config`
+    pub config: Vec<(String, String)>,
+    /// Options appended to the ones of the subcommand
+    pub options: Vec<String>,
+}
+
+impl ExtraArgs {
+    /// Parse the extra arguments of the clone and fetch of an origin
+    pub fn for_fetch(args: &[String]) -> Result<ExtraArgs, String> {
+        ExtraArgs::parse(args, &FETCH_OPTIONS)
+    }
+
+    /// Parse the extra arguments of a push
+    pub fn for_push(args: &[String]) -> Result<ExtraArgs, String> {
+        ExtraArgs::parse(args, &PUSH_OPTIONS)
+    }
+
+    /// Only allowed options and `-c <key>=<value>` with an allowed key are accepted
+    ///
+    /// Like the git config, the arguments come from descriptions, so options that run programs
+    /// (e.g. `--upload-pack`) and arguments that aren't options, which git would take as remotes,
+    /// refspecs or subcommands, are rejected.
+    fn parse(args: &[String], allowed: &[&str]) -> Result<ExtraArgs, String> {
+        let mut extra = ExtraArgs::default();
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if arg.contains(['\n', '\0']) {
+                return Err(format!("`{}` contains a newline or NUL", arg.escape_debug()));
+            }
+            if arg == "-c" {
+                let pair = args.next().ok_or("`-c` without `<key>=<value>`")?;
+                let (key, value) = pair.split_once('=').ok_or_else(|| {
+                    format!("`-c {}` is not of the form `-c <key>=<value>`", pair)
+                })?;
+                extra.config.push((key.to_owned(), value.to_owned()));
+                continue;
+            }
+            let name = match arg.find('=') {
+                Some(i) => &arg[..i + 1],
+                None => arg.as_str(),
+            };
+            if !allowed.contains(&name) {
+                return Err(format!(
+                    "`{}` is not allowed, only -c <key>=<value> and {} can be used",
+                    arg,
+                    allowed.join(", ")
+                ));
+            }
+            extra.options.push(arg.clone());
+        }
+        validate_config(&extra.config)?;
+        Ok(extra)
+    }
+
+    /// The config as `-c <key>=<value>` arguments
+    fn config_args(&self) -> Vec<String> {
+        self.config
+            .iter()
+            .flat_map(|(key, value)| vec!["-c".to_owned(), format!("{}={}", key, value)])
+            .collect()
+    }
+}
+
+/// A change a push would make to a ref of the destination, see `Git::ref_changes`
+#[derive(Debug, Clone, PartialEq)]
+pub enum RefChange {
+    Create(String),
+    /// A ref pointing to another object, with the abbreviated old and new object ids
+    Update {
+        name: String,
+        old: String,
+        new: String,
+        /// Not a fast-forward
+        forced: bool,
+    },
+    Delete(String),
+    /// The destination would refuse to update the ref for the given reason
+    Rejected(String, String),
+}
+
+impl fmt::Display for RefChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RefChange::Create(ref name) => write!(f, "create {}", name),
+            RefChange::Update { ref name, ref old, ref new, forced } => {
+                write!(f, "update {} {} -> {}", name, old, new)?;
+                if forced {
+                    write!(f, " (forced)")?;
+                }
+                Ok(())
+            }
+            RefChange::Delete(ref name) => write!(f, "delete {}", name),
+            RefChange::Rejected(ref name, ref reason) => write!(f, "reject {} ({})", name, reason),
+        }
+    }
+}
+
+/// Parse the output of `git push --porcelain`
+///
+/// Ref lines have the form `<flag>\t<from>:<to>\t<summary>`, refs already up to date are left out.
+fn parse_porcelain(out: &str) -> Vec<RefChange> {
+    out.lines()
+        .filter_map(|l| {
+            let mut fields = l.splitn(3, '\t');
+            let flag = fields.next()?;
+            let name = fields.next()?.rsplit(':').next()?.to_owned();
+            let summary = fields.next().unwrap_or("");
+            Some(match flag {
+                "*" => RefChange::Create(name),
+                "-" => RefChange::Delete(name),
+                " " | "+" => {
+                    // `old..new` for fast-forwards, `old...new (forced update)` otherwise
+                    let ids = summary.split_whitespace().next().unwrap_or("");
+                    let (old, new) = ids.split_once("...").or_else(|| ids.split_once(".."))?;
+                    RefChange::Update {
+                        name,
+                        old: old.to_owned(),
+                        new: new.to_owned(),
+                        forced: flag == "+",
+                    }
+                }
+                "!" => {
+                    let reason = summary
+                        .split_once('(')
+                        .map(|(_, r)| r.trim_end_matches(')'))
+                        .unwrap_or(summary);
+                    RefChange::Rejected(name, reason.to_owned())
+                }
+                _ => return None,
+            })
+        })
+        .collect()
+}
+
+/// The outcome of a push that didn't fail
+#[derive(Debug, PartialEq)]
+pub enum Push {
+    Done,
+    /// The destination already has the refs of the origin, see `Git::destination_refs`
+    UpToDate,
+    /// The push was skipped for the given reason
+    Skipped(String),
+}
+
+/// A failed push
+#[derive(Debug, Clone)]
+pub struct PushError {
+    pub message: String,
+    /// The destination couldn't be reached, e.g. because the connection was refused
+    pub unreachable: bool,
+}
+
+impl From<String> for PushError {
+    fn from(message: String) -> PushError {
+        PushError {
+            message,
+            unreachable: false,
+        }
+    }
+}
+
+impl From<CmdError> for PushError {
+    fn from(e: CmdError) -> PushError {
+        PushError {
+            unreachable: is_connection_error(&e.stderr),
+            message: e.message,
+        }
+    }
+}
+
+/// Whether the stderr of a git command shows that the remote couldn't be reached
+fn is_connection_error(stderr: &str) -> bool {
+    const PATTERNS: [&str; 8] = [
+        "could not resolve host",
+        "connection refused",
+        "connection timed out",
+        "failed to connect",
+        "network is unreachable",
+        "no route to host",
+        "connection reset",
+        "operation timed out",
+    ];
+    let stderr = stderr.to_lowercase();
+    PATTERNS.iter().any(|p| stderr.contains(p))
+}
+
+/// The refs a push was rejected for by name on the destination, with the reason
+///
+/// From the stderr of `git push`, e.g. ` ! [remote rejected] master -> master (non-fast-forward)`,
+/// deletions are reported without the arrow, e.g. ` ! [remote rejected] master (deletion prohibited)`.
+fn rejected_refs(stderr: &str) -> Vec<(String, String)> {
+    stderr
+        .lines()
+        .filter_map(|l| {
+            let rest = l.trim().strip_prefix('!')?.trim_start().strip_prefix('[')?;
+            let (refs, reason) = rest.split_once(']')?.1.trim().strip_suffix(')')?.rsplit_once(" (")?;
+            let name = refs.rsplit(" -> ").next()?.trim();
+            Some((name.to_owned(), reason.to_owned()))
+        })
+        .collect()
+}
+
+/// Refs the destination kept because the origin doesn't contain their history
+///
+/// Pushes are forced, so git itself only rejects them (`fetch first`) for custom refspecs without `+`.
+/// Otherwise the destination refuses non fast-forward updates with `receive.denyNonFastForwards`, which
+/// doesn't prevent deleting the ref, so `OnConflict::ForceOverwrite` can delete and push them again.
+fn conflicting_refs(rejected: &[(String, String)]) -> Vec<String> {
+    rejected
+        .iter()
+        .filter(|&(_, reason)| reason == "non-fast-forward" || reason == "fetch first")
+        .map(|(name, _)| name.clone())
+        .collect()
+}
+
+/// Refs a hook of the destination declined, which is how GitLab and GitHub protect branches
+///
+/// Protected branches can be neither force pushed nor deleted, whatever `OnConflict` says.
+fn declined_refs(rejected: &[(String, String)]) -> Vec<String> {
+    rejected
+        .iter()
+        .filter(|&(_, reason)| reason.ends_with("hook declined"))
+        .map(|(name, reason)| format!("{} ({})", name, reason))
+        .collect()
+}
+
+/// The major and minor version from the output of `git --version`, e.g. `git version 2.39.2`
+fn parse_version(out: &str) -> Option<(u32, u32)> {
+    let mut numbers = out.trim().strip_prefix("git version ")?.split(['.', ' ']);
+    Some((numbers.next()?.parse().ok()?, numbers.next()?.parse().ok()?))
+}
+
+/// Whether the local mirror in origin_dir only contains part of the history
+///
+/// Mirroring a shallow clone creates a shallow mirror, which destinations refuse.
+fn is_shallow(origin_dir: &Path) -> bool {
+    origin_dir.join("shallow").exists() || origin_dir.join("info").join("grafts").exists()
+}
+
+/// Lock files of git in the local mirror in origin_dir, e.g. `shallow.lock` or `refs/heads/main.lock`
+fn lock_files(origin_dir: &Path) -> Vec<PathBuf> {
+    fn find(dir: &Path, recursive: bool, locks: &mut Vec<PathBuf>) {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.filter_map(|e| e.ok()) {
+            let path = entry.path();
+            if path.is_dir() {
+                if recursive {
+                    find(&path, recursive, locks);
+                }
+            } else if path.extension().and_then(|e| e.to_str()) == Some("lock") {
+                locks.push(path);
+            }
+        }
+    }
+
+    let mut locks = Vec::new();
+    find(origin_dir, false, &mut locks);
+    find(&origin_dir.join("refs"), true, &mut locks);
+    locks
+}
+
+/// Whether a process is running inside dir, e.g. a git command of another worker with the same origin
+#[cfg(target_os = "linux")]
+fn dir_in_use(dir: &Path) -> bool {
+    let dir = match dir.canonicalize() {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let procs = match fs::read_dir("/proc") {
+        Ok(procs) => procs,
+        Err(_) => return false,
+    };
+    procs.filter_map(|p| p.ok()).any(|p| {
+        fs::read_link(p.path().join("cwd"))
+            .map(|cwd| cwd.starts_with(&dir))
+            .unwrap_or(false)
+    })
+}
+
+/// Whether a process is running inside dir
+///
+/// Only the run holding the lock of the mirror directory uses the local mirrors, so this is only
+/// relevant for mirrors sharing an origin, which can't be detected on this platform.
+#[cfg(not(target_os = "linux"))]
+fn dir_in_use(_dir: &Path) -> bool {
+    false
+}
+
+/// The git operations a worker uses to sync a mirror, implemented by `Git` with the git binary
+///
+/// See `Git` for the documentation of the operations.
+pub trait GitBackend {
+    fn origin_dir(&self, mirror_dir: &str, origin: &str) -> PathBuf;
+    fn origin_refs(&self, mirror_dir: &str, origin: &str, refs: Refs) -> Result<BTreeMap<String, String>, String>;
+    fn destination_refs(&self, destination: &str, refs: Refs) -> Result<BTreeMap<String, String>, PushError>;
+    fn fetch_repo(&self, mirror_dir: &str, origin: &str, partial: bool) -> Result<PathBuf, String>;
+    fn submodule_urls(&self, origin_dir: &Path) -> Result<Vec<String>, String>;
+    fn check_limits(&self, origin_dir: &Path, refs: Refs) -> Result<(), String>;
+    fn fetch_smoke(&self, mirror_dir: &str, origin: &str) -> Result<PathBuf, String>;
+    fn push_smoke(&self, smoke_dir: &Path, destination: &str) -> Result<Push, PushError>;
+    fn remove_smoke(&self, smoke_dir: &Path);
+    fn check_partial_support(&self, destination: &str);
+    fn maintain(&self, origin_dir: &Path) -> Result<(), String>;
+    fn ref_changes(&self, origin_dir: &Path, destination: &str, refs: Refs) -> Result<Vec<RefChange>, PushError>;
+    fn push_repo(
+        &self,
+        origin_dir: &Path,
+        destination: &str,
+        refs: Refs,
+        on_conflict: OnConflict,
+    ) -> Result<Push, PushError>;
+}
+
+impl GitBackend for Git {
+    fn origin_dir(&self, mirror_dir: &str, origin: &str) -> PathBuf {
+        Git::origin_dir(self, mirror_dir, origin)
+    }
+
+    fn origin_refs(&self, mirror_dir: &str, origin: &str, refs: Refs) -> Result<BTreeMap<String, String>, String> {
+        Git::origin_refs(self, mirror_dir, origin, refs)
+    }
+
+    fn destination_refs(&self, destination: &str, refs: Refs) -> Result<BTreeMap<String, String>, PushError> {
+        Git::destination_refs(self, destination, refs)
+    }
+
+    fn fetch_repo(&self, mirror_dir: &str, origin: &str, partial: bool) -> Result<PathBuf, String> {
+        Git::fetch_repo(self, mirror_dir, origin, partial)
+    }
+
+    fn submodule_urls(&self, origin_dir: &Path) -> Result<Vec<String>, String> {
+        Git::submodule_urls(self, origin_dir)
+    }
+
+    fn check_limits(&self, origin_dir: &Path, refs: Refs) -> Result<(), String> {
+        Git::check_limits(self, origin_dir, refs)
+    }
+
+    fn fetch_smoke(&self, mirror_dir: &str, origin: &str) -> Result<PathBuf, String> {
+        Git::fetch_smoke(self, mirror_dir, origin)
+    }
+
+    fn push_smoke(&self, smoke_dir: &Path, destination: &str) -> Result<Push, PushError> {
+        Git::push_smoke(self, smoke_dir, destination)
+    }
+
+    fn remove_smoke(&self, smoke_dir: &Path) {
+        Git::remove_smoke(self, smoke_dir)
+    }
+
+    fn check_partial_support(&self, destination: &str) {
+        Git::check_partial_support(self, destination)
+    }
+
+    fn maintain(&self, origin_dir: &Path) -> Result<(), String> {
+        Git::maintain(self, origin_dir)
+    }
+
+    fn ref_changes(&self, origin_dir: &Path, destination: &str, refs: Refs) -> Result<Vec<RefChange>, PushError> {
+        Git::ref_changes(self, origin_dir, destination, refs)
+    }
+
+    fn push_repo(
+        &self,
+        origin_dir: &Path,
+        destination: &str,
+        refs: Refs,
+        on_conflict: OnConflict,
+    ) -> Result<Push, PushError> {
+        Git::push_repo(self, origin_dir, destination, refs, on_conflict)
+    }
+}
+
+impl Git {
+    // Group common setting for al git commands in this function
+    fn base_cmd(&self) -> Command {
+        let mut git = Command::new("git");
+        if !log_enabled!(Debug) {
+            git.stdout(Stdio::null());
+        }
+        debug!("Level {:?}", log_enabled!(Info));
+        git.env("GIT_TERMINAL_PROMPT", "0");
+        for (key, value) in &self.config {
+            git.arg("-c").arg(format!("{}={}", key, value));
+        }
+        if self.isolate_credentials {
+            // Only credentials contained in the remote urls or the ssh setup are used,
+            // so a credential of one mirror can't be used for the remote of another one
+            git.env("GIT_CONFIG_NOSYSTEM", "1")
+                .env_remove("GIT_ASKPASS")
+                .env_remove("SSH_ASKPASS")
+                .args(["-c", "credential.helper=", "-c", "core.askPass="]);
+        }
+        // Put git into its own process group so a timeout also kills its helpers (ssh, remote-https)
+        #[cfg(unix)]
+        git.process_group(0);
+        git
+    }
+
+    /// Write the origin credentials to a file for the credential helper of `origin_cmd`
+    ///
+    /// Each worker thread uses its own file, so concurrent fetches of the same origin don't interfere.
+    fn credentials_file(
+        &self,
+        mirror_dir: &str,
+        origin: &str,
+    ) -> Result<Option<CredentialsFile>, String> {
+        let credentials = match self.origin_credentials {
+            Some(ref c) => c,
+            None => return Ok(None),
+        };
+        let dir = Path::new(mirror_dir).join(CREDENTIALS_DIR);
+        fs::create_dir_all(&dir).and_then(|_| dir.canonicalize()).map(|dir| {
+            let name = format!("{}-{:?}", origin, thread::current().id());
+            dir.join(slugify(name))
+        })
+            .map_err(|e| {
+                format!("Unable to create credentials dir {:?} ({})", dir, e)
+            })
+            .and_then(|path| CredentialsFile::create(path, credentials))
+            .map(Some)
+    }
+
+    /// A git command for the origin, answering credential requests from the credentials file
+    ///
+    /// Other credential helpers are not asked, the origin credentials are only used by this command.
+    fn origin_cmd(&self, credentials: Option<&CredentialsFile>) -> Command {
+        let mut git = self.base_cmd();
+        if let Some(credentials) = credentials {
+            git.env(CREDENTIALS_ENV, &credentials.path)
+                .args(["-c", "credential.helper=", "-c"])
+                .arg(format!("credential.helper={}", CREDENTIAL_HELPER));
+        }
+        git
+    }
+
+    /// Check that git is installed
+    fn check_version(&self) -> Result<(), String> {
+        let out = self.base_cmd().arg("--version").stdout(Stdio::piped()).output().map_err(|e| {
+            format!(
+                "Unable to execute git --version, make sure git is installed. ({})",
+                e
+            )
+        })?;
+        let version = String::from_utf8_lossy(&out.stdout);
+        if self.renames_branches && parse_version(&version).is_some_and(|v| v < NEGATIVE_REFSPECS_VERSION) {
+            return Err(format!(
+                "branch_map needs negative refspecs of git {}.{} or newer, found {}",
+                NEGATIVE_REFSPECS_VERSION.0,
+                NEGATIVE_REFSPECS_VERSION.1,
+                version.trim()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Remove lock files left behind by git commands of an interrupted run
+    fn remove_stale_locks(&self, origin_dir: &Path) {
+        let locks = lock_files(origin_dir);
+        if locks.is_empty() {
+            return;
+        }
+        if dir_in_use(origin_dir) {
+            debug!("Keeping lock files in {:?}, a process is using it", origin_dir);
+            return;
+        }
+        for lock in locks {
+            warn!("Removing stale lock file: {:?}", lock);
+            if let Err(e) = fs::remove_file(&lock) {
+                warn!("Unable to remove stale lock file {:?} ({})", lock, e);
+            }
+        }
+    }
+
+    /// The path of the local mirror of origin
+    pub fn origin_dir(&self, mirror_dir: &str, origin: &str) -> PathBuf {
+        Path::new(mirror_dir).join(self.naming_scheme.local_dir(origin))
+    }
+
+    /// The refs of remote selected by refs with their object ids, as listed by `git ls-remote`
+    ///
+    /// `HEAD` is left out, it is a symbolic ref whose target may be named differently on each remote.
+    fn ls_remote(
+        &self,
+        mut cmd: Command,
+        remote: &str,
+        refs: Refs,
+    ) -> Result<BTreeMap<String, String>, PushError> {
+        cmd.arg("ls-remote");
+        if refs == Refs::BranchesAndTags {
+            cmd.args(["--heads", "--tags"]);
+        }
+        cmd.arg(remote).stdout(Stdio::piped());
+        let out = run_cmd(&mut cmd, "List remote refs", remote, self.deadline)?;
+        // Lines of the form `<object id>\t<ref>`
+        Ok(
+            out.lines()
+                .filter_map(|l| l.split_once('\t'))
+                .filter(|&(_, name)| name != "HEAD")
+                .map(|(id, name)| (name.to_owned(), id.to_owned()))
+                .collect(),
+        )
+    }
+
+    /// The refs of origin that would be pushed, see `ls_remote`
+    ///
+    /// With the freshness probe, HTTP(S) origins are probed first, if that fails `git ls-remote` is used.
+    pub fn origin_refs(
+        &self,
+        mirror_dir: &str,
+        origin: &str,
+        refs: Refs,
+    ) -> Result<BTreeMap<String, String>, String> {
+        if self.freshness_probe && is_http(origin) {
+            match probe_refs(origin, self.origin_credentials.as_ref(), self.deadline) {
+                Ok(probed) => {
+                    return Ok(
+                        probed
+                            .into_iter()
+                            .filter(|(name, _)| {
+                                refs != Refs::BranchesAndTags || name.starts_with("refs/heads/") ||
+                                    name.starts_with("refs/tags/")
+                            })
+                            .collect(),
+                    )
+                }
+                Err(e) => debug!("Unable to probe {}, using git ls-remote ({})", origin, e),
+            }
+        }
+        let credentials = self.credentials_file(mirror_dir, origin)?;
+        self.ls_remote(self.origin_cmd(credentials.as_ref()), origin, refs)
+            .map_err(|e| e.message)
+    }
+
+    /// The refs of destination that would be updated by a push, see `ls_remote`
+    pub fn destination_refs(
+        &self,
+        destination: &str,
+        refs: Refs,
+    ) -> Result<BTreeMap<String, String>, PushError> {
+        self.ls_remote(self.base_cmd(), destination, refs)
+    }
+
+    /// Clone or update the local mirror of origin and return its path
+    ///
+    /// A partial mirror is cloned without blobs (`--filter=blob:none`), git fetches the blobs needed
+    /// by a push from the origin on demand. This only applies to new clones, an existing local mirror
+    /// keeps its mode.
+    pub fn fetch_repo(
+        &self,
+        mirror_dir: &str,
+        origin: &str,
+        partial: bool,
+    ) -> Result<PathBuf, String> {
+        let origin_dir = self.origin_dir(mirror_dir, origin);
+        debug!("Using origin dir: {0:?}", origin_dir);
+
+        self.check_version()?;
+        let credentials = self.credentials_file(mirror_dir, origin)?;
+
+        if origin_dir.is_dir() {
+            info!("Local Update for {}", origin);
+
+            if !self.keep_locks {
+                self.remove_stale_locks(&origin_dir);
+            }
+
+            let mut set_url_cmd = self.base_cmd();
+            set_url_cmd
+                .current_dir(&origin_dir)
+                .args(["remote", "set-url", "origin"])
+                .arg(origin);
+            run_cmd(&mut set_url_cmd, "Set url", origin, self.deadline)?;
+
+            // The mirror clone fetches all refs of origin
+            let mut fetch_cmd = self.origin_cmd(credentials.as_ref());
+            fetch_cmd
+                .current_dir(&origin_dir)
+                .args(self.clone_args.config_args())
+                .args(["fetch", "--progress"])
+                .args(&self.clone_args.options)
+                .arg("origin");
+            run_cmd(&mut fetch_cmd, "Fetch", origin, self.deadline)?;
+
+        } else if !origin_dir.exists() {
+            info!("Local Checkout for {}", origin);
+
+            let mut clone_cmd = self.origin_cmd(credentials.as_ref());
+            clone_cmd.args(self.clone_args.config_args()).args(
+                ["clone", "--mirror", "--progress"],
+            );
+            if partial {
+                clone_cmd.arg("--filter=blob:none");
+            }
+            clone_cmd.args(&self.clone_args.options).arg(origin).arg(&origin_dir);
+            if let Err(e) = run_cmd(&mut clone_cmd, "Clone", origin, self.deadline) {
+                // A killed clone leaves a partial directory behind, start fresh on the next run
+                if origin_dir.exists() {
+                    debug!("Removing partial clone: {:?}", origin_dir);
+                    if let Err(e) = fs::remove_dir_all(&origin_dir) {
+                        warn!("Unable to remove partial clone {:?} ({})", origin_dir, e);
+                    }
+                }
+                return Err(e.into());
+            }
+
+        } else {
+            return Err(format!("Local origin dir is a file: {:?}", origin_dir));
+        }
+
+        if is_shallow(&origin_dir) {
+            if self.unshallow {
+                info!("Unshallow local mirror of {}", origin);
+                let mut unshallow_cmd = self.origin_cmd(credentials.as_ref());
+                unshallow_cmd.current_dir(&origin_dir).args(
+                    ["fetch", "--unshallow", "--progress", "origin"],
+                );
+                if let Err(e) = run_cmd(&mut unshallow_cmd, "Unshallow", origin, self.deadline) {
+                    warn!("Unable to unshallow {} ({})", origin, e.message);
+                }
+            }
+            if is_shallow(&origin_dir) {
+                return Err(format!(
+                    "Shallow origin: {} is a shallow clone, a mirror requires the full history. \
+                     Use a full clone as origin{}.",
+                    origin,
+                    if self.unshallow { "" } else { " or try --unshallow" }
+                ));
+            }
+        }
+
+        Ok(origin_dir)
+    }
+
+    /// The urls of the submodules in the `.gitmodules` of the default branch in origin_dir
+    pub fn submodule_urls(&self, origin_dir: &Path) -> Result<Vec<String>, String> {
+        let mut exists_cmd = self.base_cmd();
+        exists_cmd
+            .current_dir(origin_dir)
+            .args(["cat-file", "-e", "HEAD:.gitmodules"])
+            .stderr(Stdio::null());
+        trace!("Check for submodules: {:?}", exists_cmd);
+        match exists_cmd.status() {
+            Ok(status) if status.success() => (),
+            // No .gitmodules or an empty repository
+            Ok(_) => return Ok(Vec::new()),
+            Err(e) => return Err(format!("Unable to check for submodules ({})", e)),
+        }
+
+        let mut config_cmd = self.base_cmd();
+        config_cmd.current_dir(origin_dir).args(
+            [
+                "config",
+                "--blob",
+                "HEAD:.gitmodules",
+                "--get-regexp",
+                r"^submodule\..*\.url$",
+            ],
+        );
+        // Lines of the form `submodule.<name>.url <url>`
+        Ok(
+            output(&mut config_cmd, "List submodules")?
+                .lines()
+                .filter_map(|l| l.split_once(' ').map(|(_, url)| url))
+                .map(|url| url.trim().to_owned())
+                .filter(|url| !url.is_empty())
+                .collect(),
+        )
+    }
+
+    /// Make sure the local mirror in origin_dir is within the limits before pushing it
+    ///
+    /// Only branches and tags count towards the ref limit if only those are pushed,
+    /// otherwise all refs of the local mirror count.
+    pub fn check_limits(&self, origin_dir: &Path, refs: Refs) -> Result<(), String> {
+        if let Some(max_refs) = self.limits.max_refs {
+            let mut refs_cmd = self.base_cmd();
+            refs_cmd.current_dir(origin_dir).args(
+                ["for-each-ref", "--format=%(refname)"],
+            );
+            if refs == Refs::BranchesAndTags {
+                refs_cmd.args(["refs/heads", "refs/tags"]);
+            }
+            let refs = output(&mut refs_cmd, "List refs")?.lines().count();
+            if refs > max_refs {
+                return Err(format!(
+                    "Limit exceeded: {} refs, at most {} allowed",
+                    refs,
+                    max_refs
+                ));
+            }
+        }
+
+        if let Some(max_pack_bytes) = self.limits.max_pack_bytes {
+            let mut count_cmd = self.base_cmd();
+            count_cmd.current_dir(origin_dir).args(["count-objects", "-v"]);
+            // Sizes of loose objects (size) and packs (size-pack) in KiB
+            let kib: u64 = output(&mut count_cmd, "Count objects")?
+                .lines()
+                .filter_map(|l| {
+                    let mut kv = l.splitn(2, ": ");
+                    match (kv.next(), kv.next()) {
+                        (Some("size"), Some(v)) |
+                        (Some("size-pack"), Some(v)) => v.trim().parse::<u64>().ok(),
+                        _ => None,
+                    }
+                })
+                .sum();
+            let bytes = kib * 1024;
+            if bytes > max_pack_bytes {
+                return Err(format!(
+                    "Limit exceeded: {} bytes of objects, at most {} allowed",
+                    bytes,
+                    max_pack_bytes
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Run `git gc` on the local mirror in origin_dir if it has more loose objects than the gc threshold
+    ///
+    /// Every fetch adds loose objects until git packs them, which slows down long lived mirrors.
+    pub fn maintain(&self, origin_dir: &Path) -> Result<(), String> {
+        let threshold = match self.gc_threshold {
+            Some(threshold) => threshold,
+            None => return Ok(()),
+        };
+        let mut count_cmd = self.base_cmd();
+        count_cmd.current_dir(origin_dir).args(["count-objects", "-v"]);
+        let loose = output(&mut count_cmd, "Count objects")?
+            .lines()
+            .filter_map(|l| l.strip_prefix("count: "))
+            .filter_map(|v| v.trim().parse::<u64>().ok())
+            .next()
+            .unwrap_or(0);
+        if loose <= threshold {
+            debug!("{} loose objects in {:?}, no gc needed", loose, origin_dir);
+            return Ok(());
+        }
+
+        info!("Running git gc in {:?}, {} loose objects exceed {}", origin_dir, loose, threshold);
+        let start = Instant::now();
+        let mut gc_cmd = self.base_cmd();
+        gc_cmd.current_dir(origin_dir).args(["gc", "--quiet"]);
+        run_cmd(&mut gc_cmd, "Gc", &origin_dir.to_string_lossy(), self.deadline)?;
+        info!("Ran git gc in {:?} in {:.1}s", origin_dir, start.elapsed().as_secs_f64());
+        Ok(())
+    }
+
+    /// Clone only the default branch of origin with depth 1 into a temporary directory and return its path
+    ///
+    /// The persistent local mirror isn't touched, the clone has to be removed with `remove_smoke`.
+    pub fn fetch_smoke(&self, mirror_dir: &str, origin: &str) -> Result<PathBuf, String> {
+        let smoke_dir = Path::new(mirror_dir).join(SMOKE_DIR).join(
+            self.naming_scheme.local_dir(origin),
+        );
+        debug!("Using smoke test dir: {:?}", smoke_dir);
+
+        self.check_version()?;
+        self.remove_smoke(&smoke_dir);
+        let credentials = self.credentials_file(mirror_dir, origin)?;
+
+        info!("Smoke test checkout for {}", origin);
+        let mut clone_cmd = self.origin_cmd(credentials.as_ref());
+        clone_cmd
+            .args(["clone", "--bare", "--single-branch", "--depth", "1", "--progress"])
+            .arg(origin)
+            .arg(&smoke_dir);
+        if let Err(e) = run_cmd(&mut clone_cmd, "Clone", origin, self.deadline) {
+            self.remove_smoke(&smoke_dir);
+            return Err(e.into());
+        }
+
+        Ok(smoke_dir)
+    }
+
+    /// Check that the default branch in smoke_dir could be pushed to destination, without changing it
+    ///
+    /// The destination is contacted with `git push --dry-run`, which checks connectivity and authentication.
+    pub fn push_smoke(&self, smoke_dir: &Path, destination: &str) -> Result<Push, PushError> {
+        info!("Smoke test push to destination {}", destination);
+
+        let mut push_cmd = self.base_cmd();
+        push_cmd
+            .current_dir(smoke_dir)
+            .args(["push", "--dry-run", "--progress"])
+            .arg(destination)
+            .arg("HEAD");
+        run_cmd(&mut push_cmd, "Push", destination, self.deadline)?;
+        Ok(Push::Done)
+    }
+
+    /// Remove the temporary clone of a smoke test
+    pub fn remove_smoke(&self, smoke_dir: &Path) {
+        if smoke_dir.exists() {
+            debug!("Removing smoke test dir: {:?}", smoke_dir);
+            if let Err(e) = fs::remove_dir_all(smoke_dir) {
+                warn!("Unable to remove smoke test dir {:?} ({})", smoke_dir, e);
+            }
+        }
+    }
+
+    /// Warn if destination can't serve partial clones, so a partial mirror would be of limited use
+    pub fn check_partial_support(&self, destination: &str) {
+        let mut ls_cmd = self.base_cmd();
+        ls_cmd
+            .env("GIT_TRACE_PACKET", "1")
+            .args(["-c", "protocol.version=2", "ls-remote", "--heads"])
+            .arg(destination)
+            .stdout(Stdio::null());
+        trace!("Check partial clone support: {:?}", ls_cmd);
+        // The capabilities are only visible in the packet trace, e.g. `fetch=shallow filter`
+        let supported = match ls_cmd.output() {
+            Ok(out) => {
+                String::from_utf8_lossy(&out.stderr).lines().any(|l| {
+                    l.contains("fetch=") && l.split_whitespace().any(|c| c == "filter")
+                })
+            }
+            Err(e) => {
+                debug!("Unable to check partial clone support of {} ({})", destination, e);
+                false
+            }
+        };
+        if !supported {
+            warn!(
+                "Destination {} doesn't announce partial clone support (filter), \
+                 mirroring a partial clone to it may not be useful",
+                destination
+            );
+        }
+    }
+
+    /// A `git push` of refs in the local mirror in origin_dir to destination, options are added after `push`
+    fn push_cmd(&self, origin_dir: &Path, destination: &str, refs: Refs, options: &[&str]) -> Command {
+        let mut push_cmd = self.base_cmd();
+        push_cmd
+            .current_dir(origin_dir)
+            .args(self.push_args.config_args())
+            .arg("push")
+            .args(options)
+            .args(&self.push_args.options);
+        match refs {
+            Refs::All => {
+                push_cmd.args(["--mirror", "--progress"]).arg(destination);
+            }
+            Refs::BranchesAndTags => {
+                // Same as --mirror, limited to the branches and tags
+                push_cmd
+                    .args(["--prune", "--progress"])
+                    .arg(destination)
+                    .args(BRANCHES_AND_TAGS);
+            }
+            Refs::Custom(refspecs) => {
+                push_cmd.arg("--progress").arg(destination).args(refspecs);
+            }
+        }
+        push_cmd
+    }
+
+    /// The changes pushing the local mirror in origin_dir to destination would make, without pushing
+    ///
+    /// Git computes the changes with `git push --dry-run --porcelain`, so they match a real push exactly.
+    /// If the destination can't be read at all, e.g. because it doesn't exist yet, every ref that would
+    /// be pushed is reported as created. This isn't possible for custom refspecs.
+    pub fn ref_changes(
+        &self,
+        origin_dir: &Path,
+        destination: &str,
+        refs: Refs,
+    ) -> Result<Vec<RefChange>, PushError> {
+        info!("Dry run push to destination {}", destination);
+
+        let mut push_cmd = self.push_cmd(origin_dir, destination, refs, &["--dry-run", "--porcelain"]);
+        push_cmd.stdout(Stdio::piped());
+        let e = match run_cmd(&mut push_cmd, "Dry run push", destination, self.deadline) {
+            Ok(out) => return Ok(parse_porcelain(&out)),
+            Err(e) => e,
+        };
+
+        if matches!(refs, Refs::Custom(_)) || self.destination_refs(destination, Refs::All).is_ok() {
+            return Err(e.into());
+        }
+        debug!("Destination {} can't be read, all refs would be created ({})", destination, e.message);
+        let mut refs_cmd = self.base_cmd();
+        refs_cmd.current_dir(origin_dir).args(
+            ["for-each-ref", "--format=%(refname)"],
+        );
+        if refs == Refs::BranchesAndTags {
+            refs_cmd.args(["refs/heads", "refs/tags"]);
+        }
+        Ok(
+            output(&mut refs_cmd, "List refs")?
+                .lines()
+                .map(|name| RefChange::Create(name.to_owned()))
+                .collect(),
+        )
+    }
+
+    /// Push the local mirror in origin_dir to destination
+    ///
+    /// Unless all refs are pushed, refs not matched by refs are left alone on the destination.
+    pub fn push_repo(
+        &self,
+        origin_dir: &Path,
+        destination: &str,
+        refs: Refs,
+        on_conflict: OnConflict,
+    ) -> Result<Push, PushError> {
+        info!("Push to destination {}", destination);
+
+        let mut push_cmd = self.push_cmd(origin_dir, destination, refs, &[]);
+        let e = match run_cmd(&mut push_cmd, "Push", destination, self.deadline) {
+            Ok(_) => return Ok(Push::Done),
+            Err(e) => e,
+        };
+
+        let rejected = rejected_refs(&e.stderr);
+        let refs = conflicting_refs(&rejected);
+        if refs.is_empty() {
+            let declined = declined_refs(&rejected);
+            if !declined.is_empty() {
+                return Err(
+                    format!(
+                        "Destination declined the push of: {}, e.g. because they are protected branches ({})",
+                        declined.join(", "),
+                        e.message
+                    ).into(),
+                );
+            }
+            return Err(e.into());
+        }
+
+        match on_conflict {
+            OnConflict::Fail => Err(
+                format!(
+                    "Destination has unrelated history for: {} ({})",
+                    refs.join(", "),
+                    e.message
+                ).into(),
+            ),
+            OnConflict::Skip => Ok(Push::Skipped(format!(
+                "destination has unrelated history for: {}",
+                refs.join(", ")
+            ))),
+            OnConflict::ForceOverwrite => {
+                // Always visible, independent of the log level
+                println!(
+                    "OVERWRITE [{}]: {} ({})",
+                    Local::now(),
+                    destination,
+                    refs.join(", ")
+                );
+                warn!(
+                    "Overwriting history of {} in destination {}",
+                    refs.join(", "),
+                    destination
+                );
+
+                // The destination refuses non fast-forward updates, so delete the refs first. This needs a
+                // destination that allows deleting them, protected branches and the default branch usually aren't.
+                let mut delete_cmd = self.base_cmd();
+                delete_cmd
+                    .current_dir(origin_dir)
+                    .args(["push", "--delete"])
+                    .arg(destination)
+                    .args(&refs);
+                run_cmd(&mut delete_cmd, "Delete conflicting refs", destination, self.deadline).map_err(|e| {
+                    let refused: Vec<String> = rejected_refs(&e.stderr)
+                        .into_iter()
+                        .map(|(name, reason)| format!("{} ({})", name, reason))
+                        .collect();
+                    if refused.is_empty() {
+                        return PushError::from(e);
+                    }
+                    format!(
+                        "Unable to overwrite the history of the destination, it refused to delete: {} ({})",
+                        refused.join(", "),
+                        e.message
+                    ).into()
+                })?;
+
+                run_cmd(&mut push_cmd, "Push", destination, self.deadline)?;
+                Ok(Push::Done)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+
+    /// What git's credential helpers answer for origin when run like the fetch of git
+    fn credential_fill(git: &Git, credentials: &CredentialsFile, origin: &str) -> String {
+        let mut fill = git.origin_cmd(Some(credentials))
+            .args(["credential", "fill"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let url = format!("url={}\n\n", origin);
+        fill.stdin.take().unwrap().write_all(url.as_bytes()).unwrap();
+        let output = fill.wait_with_output().unwrap();
+        assert!(output.status.success(), "git credential fill failed for {}", origin);
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    #[test]
+    fn mirrors_only_get_their_own_credentials() {
+        let dir = env::temp_dir().join(format!("git-mirror-credentials-{}", process::id()));
+        let mirror_dir = dir.to_string_lossy().into_owned();
+        let git = |username: &str, password: &str| Git {
+            origin_credentials: Some(Credentials {
+                username: Some(username.to_owned()),
+                password: Some(password.to_owned()),
+            }),
+            ..Git::default()
+        };
+        let (a, b) = (git("alice", "secret-a"), git("bob", "secret-b"));
+        let (origin_a, origin_b) = ("https://a.example.com/a.git", "https://b.example.com/b.git");
+
+        // Both fetches run at the same time, each with its own file
+        let file_a = a.credentials_file(&mirror_dir, origin_a).unwrap().unwrap();
+        let file_b = b.credentials_file(&mirror_dir, origin_b).unwrap().unwrap();
+        assert_ne!(file_a.path, file_b.path);
+        assert_eq!(fs::read_to_string(&file_a.path).unwrap(), "username=alice\npassword=secret-a\n");
+        assert_eq!(fs::read_to_string(&file_b.path).unwrap(), "username=bob\npassword=secret-b\n");
+
+        let filled_a = credential_fill(&a, &file_a, origin_a);
+        assert!(filled_a.contains("username=alice\n") && filled_a.contains("password=secret-a\n"));
+        assert!(!filled_a.contains("bob") && !filled_a.contains("secret-b"));
+        let filled_b = credential_fill(&b, &file_b, origin_b);
+        assert!(filled_b.contains("username=bob\n") && filled_b.contains("password=secret-b\n"));
+        assert!(!filled_b.contains("alice") && !filled_b.contains("secret-a"));
+
+        // A mirror without credentials gets no file
+        assert!(Git::default().credentials_file(&mirror_dir, origin_a).unwrap().is_none());
+
+        let (path_a, path_b) = (file_a.path.clone(), file_b.path.clone());
+        drop(file_a);
+        drop(file_b);
+        assert!(!path_a.exists() && !path_b.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn config(key: &str, value: &str) -> Vec<(String, String)> {
+        vec![(key.to_owned(), value.to_owned())]
+    }
+
+    #[test]
+    fn validate_config_allows_only_harmless_http_keys() {
+        assert!(validate_config(&config("http.postBuffer", "524288000")).is_ok());
+        assert!(validate_config(&config("HTTP.lowSpeedTime", "60")).is_ok());
+        assert!(validate_config(&config("core.compression", "9")).is_ok());
+        assert!(validate_config(&config("pack.threads", "2")).is_ok());
+
+        for key in &["http.proxy", "http.sslVerify", "http.sslCert", "http.sslKey", "http.extraHeader"] {
+            let e = validate_config(&config(key, "x")).unwrap_err();
+            assert!(e.contains("not allowed"), "{}", e);
+        }
+        // Not even the allowed keys for a single url
+        assert!(validate_config(&config("http.https://git.example.com/.postBuffer", "1")).is_err());
+        assert!(validate_config(&config("http.https://git.example.com/.proxy", "http://proxy")).is_err());
+        assert!(validate_config(&config("core.sshCommand", "sh")).is_err());
+        assert!(validate_config(&config("credential.helper", "store")).is_err());
+        assert!(validate_config(&config("http.postBuffer", "1\n[core]")).is_err());
+    }
+
+    #[test]
+    fn rejected_refs_of_push_output() {
+        let stderr = "remote: error: denying non-fast-forward refs/heads/master (you should pull first)
+To /srv/git/dst.git
+ ! [remote rejected] master -> master (non-fast-forward)
+ ! [rejected]        feature -> mirrored/feature (fetch first)
+ ! [remote rejected] release -> release (pre-receive hook declined)
+ ! [remote rejected] old (deletion prohibited)
+error: failed to push some refs to '/srv/git/dst.git'
+";
+        let rejected = rejected_refs(stderr);
+        assert_eq!(
+            rejected,
+            vec![
+                ("master".to_owned(), "non-fast-forward".to_owned()),
+                ("mirrored/feature".to_owned(), "fetch first".to_owned()),
+                ("release".to_owned(), "pre-receive hook declined".to_owned()),
+                ("old".to_owned(), "deletion prohibited".to_owned()),
+            ]
+        );
+        assert_eq!(conflicting_refs(&rejected), vec!["master", "mirrored/feature"]);
+        assert_eq!(declined_refs(&rejected), vec!["release (pre-receive hook declined)"]);
+    }
+
+    /// Object ids by ref name, each id is the name of its ref in the origin
+    fn origin(names: &[&str]) -> BTreeMap<String, String> {
+        names.iter().map(|n| (n.to_string(), n.to_string())).collect()
+    }
+
+    fn pushed(origin_refs: &BTreeMap<String, String>, refspecs: &[&str]) -> Vec<(String, String)> {
+        let refspecs: Vec<String> = refspecs.iter().map(|s| s.to_string()).collect();
+        pushed_refs(origin_refs, Refs::Custom(&refspecs)).into_iter().collect()
+    }
+
+    fn refs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|&(d, s)| (d.to_owned(), s.to_owned())).collect()
+    }
+
+    #[test]
+    fn pushed_refs_of_patterns_with_a_suffix() {
+        let origin_refs = origin(&["refs/heads/a-stable", "refs/heads/b-stable", "refs/heads/stable-c", "refs/tags/v1"]);
+        assert_eq!(
+            pushed(&origin_refs, &["+refs/heads/*-stable:refs/heads/stable/*"]),
+            refs(&[("refs/heads/stable/a", "refs/heads/a-stable"), ("refs/heads/stable/b", "refs/heads/b-stable")])
+        );
+    }
+
+    #[test]
+    fn pushed_refs_of_short_names() {
+        let origin_refs = origin(&["refs/heads/master", "refs/heads/dev", "refs/tags/v1", "refs/remotes/x/master"]);
+        assert_eq!(pushed(&origin_refs, &["master"]), refs(&[("refs/heads/master", "refs/heads/master")]));
+        assert_eq!(pushed(&origin_refs, &["master:main"]), refs(&[("refs/heads/main", "refs/heads/master")]));
+        // A short destination of a tag is a tag
+        assert_eq!(pushed(&origin_refs, &["v1:release"]), refs(&[("refs/tags/release", "refs/tags/v1")]));
+    }
+
+    #[test]
+    fn pushed_refs_without_negatively_matched_destinations() {
+        let origin_refs = origin(&["refs/heads/master", "refs/heads/x", "refs/heads/y"]);
+        assert_eq!(
+            pushed(&origin_refs, &["+refs/heads/*:refs/heads/*", "^refs/heads/x"]),
+            refs(&[("refs/heads/master", "refs/heads/master"), ("refs/heads/y", "refs/heads/y")])
+        );
+        // The negative refspec matches the destination, not the source
+        assert_eq!(
+            pushed(&origin_refs, &["+refs/heads/*:refs/heads/mirror/*", "^refs/heads/x"]),
+            refs(&[
+                ("refs/heads/mirror/master", "refs/heads/master"),
+                ("refs/heads/mirror/x", "refs/heads/x"),
+                ("refs/heads/mirror/y", "refs/heads/y"),
+            ])
+        );
+        assert_eq!(
+            pushed(&origin_refs, &["+refs/heads/*:refs/heads/*", "^refs/heads/*"]),
+            Vec::new()
+        );
+    }
+
+    #[test]
+    fn pushed_refs_of_explicit_refspecs_replace_patterns() {
+        let origin_refs = origin(&["refs/heads/master", "refs/heads/main"]);
+        // Whatever their order
+        for refspecs in [
+            ["+refs/heads/*:refs/heads/*", "+refs/heads/master:refs/heads/main"],
+            ["+refs/heads/master:refs/heads/main", "+refs/heads/*:refs/heads/*"],
+        ] {
+            assert_eq!(
+                pushed(&origin_refs, &refspecs),
+                refs(&[("refs/heads/main", "refs/heads/master"), ("refs/heads/master", "refs/heads/master")])
+            );
+        }
+    }
+
+    #[test]
+    fn pushed_refs_ignore_deletes() {
+        let origin_refs = origin(&["refs/heads/master", "refs/heads/old"]);
+        assert_eq!(
+            pushed(&origin_refs, &["refs/heads/master", ":refs/heads/old"]),
+            refs(&[("refs/heads/master", "refs/heads/master")])
+        );
+        assert_eq!(pushed(&origin_refs, &["+:refs/heads/gone"]), Vec::new());
+    }
+
+    /// Run git in dir with a fixed identity, failing the test if it fails
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .current_dir(dir)
+            .args(args)
+            .env("GIT_AUTHOR_NAME", "git-mirror")
+            .env("GIT_AUTHOR_EMAIL", "git-mirror@example.com")
+            .env("GIT_COMMITTER_NAME", "git-mirror")
+            .env("GIT_COMMITTER_EMAIL", "git-mirror@example.com")
+            .stderr(Stdio::null())
+            .stdout(Stdio::null())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed in {:?}", args, dir);
+    }
+
+    #[test]
+    fn force_overwrite_needs_a_destination_allowing_deletes() {
+        let dir = env::temp_dir().join(format!("git-mirror-conflict-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (origin, local, destination) = (dir.join("origin"), dir.join("local"), dir.join("destination"));
+        let destination_url = destination.to_string_lossy().into_owned();
+        run_git(&dir, &["init", "-q", "-b", "main", "origin"]);
+        run_git(&origin, &["commit", "-q", "--allow-empty", "-m", "origin"]);
+        run_git(&dir, &["clone", "-q", "--mirror", "origin", "local"]);
+        // The destination was created with an unrelated commit on a branch that isn't its default branch
+        run_git(&dir, &["init", "-q", "--bare", "destination"]);
+        run_git(&destination, &["symbolic-ref", "HEAD", "refs/heads/default"]);
+        run_git(&dir, &["init", "-q", "unrelated"]);
+        run_git(&dir.join("unrelated"), &["commit", "-q", "--allow-empty", "-m", "unrelated"]);
+        run_git(&dir.join("unrelated"), &["push", "-q", &destination_url, "HEAD:refs/heads/main"]);
+        run_git(&destination, &["config", "receive.denyNonFastForwards", "true"]);
+        let git = Git::default();
+
+        run_git(&destination, &["config", "receive.denyDeletes", "true"]);
+        let e = git.push_repo(&local, &destination_url, Refs::All, OnConflict::ForceOverwrite).unwrap_err();
+        assert!(e.message.contains("refused to delete"), "{}", e.message);
+        assert!(e.message.contains("(deletion prohibited)"), "{}", e.message);
+
+        run_git(&destination, &["config", "--unset", "receive.denyDeletes"]);
+        assert_eq!(git.push_repo(&local, &destination_url, Refs::All, OnConflict::ForceOverwrite).unwrap(), Push::Done);
+        assert_eq!(
+            git.destination_refs(&destination_url, Refs::All).unwrap(),
+            git.destination_refs(&local.to_string_lossy(), Refs::All).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn protected_branches_are_reported() {
+        let dir = env::temp_dir().join(format!("git-mirror-protected-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (origin, local, destination) = (dir.join("origin"), dir.join("local"), dir.join("destination"));
+        run_git(&dir, &["init", "-q", "origin"]);
+        run_git(&origin, &["commit", "-q", "--allow-empty", "-m", "origin"]);
+        run_git(&dir, &["clone", "-q", "--mirror", "origin", "local"]);
+        run_git(&dir, &["init", "-q", "--bare", "destination"]);
+        let hook = destination.join("hooks").join("pre-receive");
+        fs::write(&hook, "#!/bin/sh\necho 'You are not allowed to force push code to a protected branch' >&2\nexit 1\n")
+            .unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&hook, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+
+        let e = Git::default()
+            .push_repo(&local, &destination.to_string_lossy(), Refs::All, OnConflict::ForceOverwrite)
+            .unwrap_err();
+        assert!(e.message.contains("protected branches"), "{}", e.message);
+        assert!(e.message.contains("(pre-receive hook declined)"), "{}", e.message);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn parse_version_of_git() {
+        assert_eq!(parse_version("git version 2.39.2\n"), Some((2, 39)));
+        assert_eq!(parse_version("git version 2.28.0.windows.1"), Some((2, 28)));
+        assert_eq!(parse_version("git version 2.39.3 (Apple Git-145)"), Some((2, 39)));
+        assert_eq!(parse_version("git version 3"), None);
+        assert_eq!(parse_version("not git"), None);
+    }
+
+    #[test]
+    fn branch_map_renames_branches_on_the_destination() {
+        let dir = env::temp_dir().join(format!("git-mirror-branch-map-{}", process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (origin, local) = (dir.join("origin"), dir.join("local"));
+        run_git(&dir, &["init", "-q", "-b", "master", "origin"]);
+        // Every branch points to a commit of its own
+        for branch in &["master", "main", "a", "b", "c"] {
+            run_git(&origin, &["commit", "-q", "--allow-empty", "-m", branch]);
+            run_git(&origin, &["update-ref", &format!("refs/heads/{}", branch), "HEAD"]);
+        }
+        run_git(&origin, &["tag", "v1"]);
+        run_git(&dir, &["clone", "-q", "--mirror", "origin", "local"]);
+        let git = Git { renames_branches: true, ..Git::default() };
+        let origin_refs = git.destination_refs(&local.to_string_lossy(), Refs::All).unwrap();
+        let id = |branch: &str| origin_refs[&format!("refs/heads/{}", branch)].clone();
+
+        let maps: [&[(&str, &str)]; 3] = [
+            &[("master", "main")],
+            &[("master", "main"), ("main", "master")],
+            &[("a", "b"), ("b", "c")],
+        ];
+        let expected: [&[(&str, &str)]; 3] = [
+            &[("a", "a"), ("b", "b"), ("c", "c"), ("main", "master")],
+            &[("a", "a"), ("b", "b"), ("c", "c"), ("main", "master"), ("master", "main")],
+            &[("b", "a"), ("c", "b"), ("main", "main"), ("master", "master")],
+        ];
+        for (i, (map, expected)) in maps.iter().zip(&expected).enumerate() {
+            let branch_map: BTreeMap<String, String> =
+                map.iter().map(|&(from, to)| (from.to_owned(), to.to_owned())).collect();
+            let refspecs = branch_map_refspecs(&branch_map, false);
+            let destination = dir.join(format!("destination-{}", i));
+            run_git(&dir, &["init", "-q", "--bare", &destination.to_string_lossy()]);
+            let destination = destination.to_string_lossy().into_owned();
+
+            let push = git.push_repo(&local, &destination, Refs::Custom(&refspecs), OnConflict::Fail);
+            assert_eq!(push.unwrap(), Push::Done, "{:?}", map);
+            let mut want: BTreeMap<String, String> = expected
+                .iter()
+                .map(|&(to, from)| (format!("refs/heads/{}", to), id(from)))
+                .collect();
+            want.insert("refs/tags/v1".to_owned(), origin_refs["refs/tags/v1"].clone());
+            let pushed = git.destination_refs(&destination, Refs::All).unwrap();
+            assert_eq!(pushed, want, "{:?}", map);
+            // --verify expects the same refs
+            assert_eq!(pushed_refs(&origin_refs, Refs::Custom(&refspecs)), want, "{:?}", map);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
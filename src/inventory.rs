@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::io::{self, Write};
+
+// Used to write the JSON inventory
+extern crate serde_json;
+
+use chrono::Utc;
+
+use layout::fnv1a;
+use provider::Provider;
+use MirrorOptions;
+
+/// An origin and destination that would be mirrored, with the metadata of the project it came from
+#[derive(Debug, Clone, Serialize)]
+pub struct InventoryEntry {
+    pub name: Option<String>,
+    pub origin: String,
+    pub destination: String,
+    pub web_url: Option<String>,
+    pub visibility: Option<String>,
+    pub last_activity: Option<String>,
+    pub labels: Vec<String>,
+}
+
+/// Everything a provider would mirror, meant to be archived and compared between runs
+#[derive(Debug, Clone, Serialize)]
+pub struct Inventory {
+    /// Label of the provider the mirrors came from
+    pub provider: String,
+    /// Creation time as RFC 3339 time
+    pub generated_at: String,
+    /// Identifies the arguments the inventory was created with, see `config_hash`
+    pub config_hash: String,
+    /// Sorted by origin and destination, so inventories can be compared line by line
+    pub mirrors: Vec<InventoryEntry>,
+}
+
+impl Inventory {
+    /// List the mirrors of provider with the labels and rewrites of opts applied, without running git
+    ///
+    /// Projects skipped in their description or with an invalid description are not listed.
+    pub fn new(provider: &dyn Provider, opts: &MirrorOptions, config_hash: String) -> Result<Inventory, String> {
+        let mut mirrors = Vec::new();
+        for (m, meta) in provider.get_mirror_repos_with_meta()? {
+            let m = match m {
+                Ok(m) => opts.rewrites.apply(m),
+                Err(_) => continue,
+            };
+            if let Some(ref labels) = opts.labels {
+                if !m.labels.iter().any(|l| labels.contains(l)) {
+                    continue;
+                }
+            }
+            for destination in m.destinations() {
+                mirrors.push(InventoryEntry {
+                    name: meta.name.clone(),
+                    origin: m.origin.clone(),
+                    destination: destination.to_owned(),
+                    web_url: meta.web_url.clone(),
+                    visibility: meta.visibility.clone(),
+                    last_activity: meta.last_activity.clone(),
+                    labels: m.labels.clone(),
+                });
+            }
+        }
+        mirrors.sort_by(|a, b| {
+            (&a.origin, &a.destination).cmp(&(&b.origin, &b.destination))
+        });
+        Ok(Inventory {
+            provider: provider.get_label(),
+            generated_at: Utc::now().to_rfc3339(),
+            config_hash,
+            mirrors,
+        })
+    }
+
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+
+    /// One row per mirror after a header, the provider, time and hash are in leading `#` comment lines
+    pub fn write_csv(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "# provider: {}", self.provider)?;
+        writeln!(out, "# generated_at: {}", self.generated_at)?;
+        writeln!(out, "# config_hash: {}", self.config_hash)?;
+        writeln!(
+            out,
+            "name,origin,destination,web_url,visibility,last_activity,labels"
+        )?;
+        for m in &self.mirrors {
+            let fields = [
+                m.name.clone().unwrap_or_default(),
+                m.origin.clone(),
+                m.destination.clone(),
+                m.web_url.clone().unwrap_or_default(),
+                m.visibility.clone().unwrap_or_default(),
+                m.last_activity.clone().unwrap_or_default(),
+                m.labels.join(" "),
+            ];
+            let row: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+            writeln!(out, "{}", row.join(","))?;
+        }
+        Ok(())
+    }
+}
+
+/// Quote a CSV field if it contains a separator, quote or line break
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
+
+/// A stable hash of the arguments that decide what is mirrored, as 16 hex digits
+pub fn config_hash(args: &[(&str, Vec<String>)]) -> String {
+    let lines: String = args.iter()
+        .map(|&(name, ref values)| format!("{}={}\n", name, values.join("\0")))
+        .collect();
+    format!("{:016x}", fnv1a(&lines))
+}
@@ -0,0 +1,156 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// Used to create sane local directory names
+use slug::slugify;
+
+use url;
+
+// Longest directory name created by the namespace scheme, most filesystems allow 255 bytes
+const MAX_COMPONENT_LEN: usize = 100;
+
+// Longest relative path created by the namespace scheme
+const MAX_PATH_LEN: usize = 200;
+
+/// How the local directory of an origin inside the mirror directory is named
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum NamingScheme {
+    /// A single directory named after the slugified origin url
+    #[default]
+    Slug,
+    /// Nested directories following the origin, e.g. `{host}/{namespace}/{repo}.git`
+    Namespace,
+}
+
+impl NamingScheme {
+    /// The directory of origin, relative to the mirror directory
+    pub fn local_dir(&self, origin: &str) -> PathBuf {
+        match *self {
+            NamingScheme::Slug => PathBuf::from(slugify(origin)),
+            NamingScheme::Namespace => namespace_dir(origin),
+        }
+    }
+}
+
+/// Derives flat destination names from nested project paths, e.g. `team/sub/repo` as `team-sub-repo`
+///
+/// For destinations that don't support nested namespaces. Names are compared case insensitively,
+/// a name colliding with the name of an earlier path is warned about and, with `disambiguate`,
+/// gets the hash of its path appended, e.g. `team-sub-repo-1a2b3c4d`.
+#[derive(Debug, Clone)]
+pub struct Flatten {
+    /// Put in front of the name, e.g. `git@github.com:mirror-org/`
+    pub prefix: String,
+    /// Joins the components of a path, e.g. `-` or `__`
+    pub separator: String,
+    pub disambiguate: bool,
+}
+
+impl Flatten {
+    /// The destinations of paths, in order, with collisions resolved
+    pub fn destinations(&self, paths: &[String]) -> Vec<String> {
+        let mut seen: HashMap<String, &str> = HashMap::new();
+        paths
+            .iter()
+            .map(|path| {
+                let mut name = path.split('/')
+                    .filter(|c| !c.is_empty())
+                    .collect::<Vec<_>>()
+                    .join(&self.separator);
+                if let Some(&other) = seen.get(&name.to_lowercase()) {
+                    if other != path {
+                        if self.disambiguate {
+                            let unique = with_hash(&name, path, name.len() + 9);
+                            warn!(
+                                "Destination name {} of {} is taken by {}, using {}",
+                                name,
+                                path,
+                                other,
+                                unique
+                            );
+                            name = unique;
+                        } else {
+                            warn!("Destination name {} of {} is taken by {}", name, path, other);
+                        }
+                    }
+                }
+                seen.entry(name.to_lowercase()).or_insert(path);
+                format!("{}{}.git", self.prefix, name)
+            })
+            .collect()
+    }
+}
+
+fn namespace_dir(origin: &str) -> PathBuf {
+    let host = url::host(origin).unwrap_or_else(|| "local".to_owned());
+    let path = url::path(origin);
+
+    let mut components: Vec<String> = path.split(['/', '\\'])
+        // Never leave the mirror directory
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .map(sanitize)
+        .collect();
+
+    let repo = components.pop().unwrap_or_else(|| "repo".to_owned());
+    let repo = repo.trim_end_matches(".git").to_owned();
+    components.push(repo);
+
+    let mut dir = PathBuf::from(sanitize(&host));
+    let len: usize = components.iter().map(|c| c.len() + 1).sum();
+    if len > MAX_PATH_LEN {
+        // Flatten to the host and the repository name, made unique by the hash of the path
+        let repo = components.pop().unwrap_or_default();
+        dir.push(format!("{}.git", with_hash(&repo, &path, MAX_COMPONENT_LEN)));
+        return dir;
+    }
+
+    let last = components.len() - 1;
+    for (i, c) in components.iter().enumerate() {
+        // Names are lowercased to avoid collisions on case insensitive filesystems,
+        // a hash of the original keeps names differing only in case apart
+        let lower = c.to_lowercase();
+        let mut name = if lower != *c || c.len() > MAX_COMPONENT_LEN {
+            with_hash(&lower, c, MAX_COMPONENT_LEN)
+        } else {
+            lower
+        };
+        if i == last {
+            name.push_str(".git");
+        }
+        dir.push(name);
+    }
+    dir
+}
+
+/// Replace everything except alphanumerics, `.`, `-` and `_`
+fn sanitize(component: &str) -> String {
+    component
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+            c
+        } else {
+            '-'
+        })
+        .collect()
+}
+
+/// Append the hash of original to name, truncating name to stay within max_len
+fn with_hash(name: &str, original: &str, max_len: usize) -> String {
+    let hash = format!("{:08x}", fnv1a(original) as u32);
+    let keep = max_len - hash.len() - 1;
+    let name: String = name.chars().take(keep).collect();
+    format!("{}-{}", name, hash)
+}
+
+/// FNV-1a, a hash that is stable across Rust versions
+pub fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
@@ -4,10 +4,10 @@
  * SPDX-License-Identifier:     MIT
  */
 
-use std::process::{Command, Stdio};
 use std::fs;
 use std::path::Path;
 use std::fs::File;
+use std::time::{Duration, Instant};
 
 // File locking
 extern crate fs2;
@@ -16,11 +16,9 @@ use fs2::FileExt;
 // Used for error and debug logging
 #[macro_use]
 extern crate log;
-use log::LogLevel::{Debug, Info};
 
 // Used to create sane local directory names
 extern crate slug;
-use self::slug::slugify;
 
 // UMacros used for hyper
 #[macro_use]
@@ -34,6 +32,8 @@ extern crate serde_derive;
 extern crate threadpool;
 use threadpool::ThreadPool;
 use std::sync::mpsc::channel;
+use std::sync::{Arc, Condvar, Mutex};
+use std::collections::{HashMap, HashSet, VecDeque};
 
 // Time handling
 extern crate chrono;
@@ -42,274 +42,869 @@ use chrono::{Local, Utc};
 // Monitoring
 #[macro_use]
 extern crate prometheus;
-use prometheus::{TextEncoder, Encoder};
+use prometheus::{Collector, CounterVec, Encoder, GaugeVec, HistogramVec, TextEncoder};
 
-use provider::{MirrorResult, Provider};
+use provider::{filter_labels, sort_mirrors, Credentials, Mirror, MirrorError, MirrorResult, Provider, SortKey};
 
+/// How to handle a destination refusing the push because it has unrelated history
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OnConflict {
+    /// Report the mirror as failed
+    #[default]
+    Fail,
+    /// Replace the history of the destination with the one of the origin, see `Git::push_repo`
+    ///
+    /// The conflicting refs are deleted first, so the destination must allow deleting them.
+    ForceOverwrite,
+    /// Leave the destination untouched and report the mirror as skipped
+    Skip,
+}
 
-pub fn mirror_repo(
-    mirror_dir: String,
-    origin: &str,
-    destination: &str,
-    dry_run: bool,
-) -> Result<u8, String> {
+/// Limits a local mirror has to stay within to be pushed, `None` means unlimited
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Limits {
+    /// Maximum number of pushed refs
+    pub max_refs: Option<usize>,
+    /// Maximum size of all objects of the local mirror in bytes
+    pub max_pack_bytes: Option<u64>,
+}
 
-    if dry_run {
-        return Ok(1);
-    }
+/// Options controlling a mirror run
+#[derive(Debug)]
+pub struct MirrorOptions {
+    pub mirror_dir: String,
+    /// How the local directories inside mirror_dir are named
+    pub naming_scheme: NamingScheme,
+    pub dry_run: bool,
+    /// With dry_run, fetch the origins and print the ref changes the pushes would make, see `Git::ref_changes`
+    pub dry_run_refs: bool,
+    pub worker_count: usize,
+    pub metrics_file: Option<String>,
+    /// Sort the mirrors before syncing to get a deterministic order, `None` keeps the order of the provider
+    pub sort: Option<SortKey>,
+    /// Maximum time a single mirror may take before its git commands are killed
+    pub timeout: Option<Duration>,
+    pub on_conflict: OnConflict,
+    /// Don't use credentials from credential helpers, askpass programs or the system git config
+    pub isolate_credentials: bool,
+    /// Try to fetch the full history of shallow origins instead of failing right away
+    pub unshallow: bool,
+    /// Mirrors exceeding these limits after fetching are not pushed
+    pub limits: Limits,
+    /// Don't remove lock files left behind in the local mirrors by interrupted runs
+    pub keep_locks: bool,
+    /// Only check that the default branch of every origin can be fetched and pushed, see `Git::push_smoke`
+    pub smoke: bool,
+    /// If set, only destinations on one of these hosts are pushed to
+    pub allowed_destination_hosts: Option<Vec<String>>,
+    /// Number of connection failures after which a destination host isn't tried anymore during a run
+    pub host_failure_budget: Option<usize>,
+    /// How many levels of nested submodules are mirrored for mirrors with `recurse_submodules`
+    pub submodule_depth: usize,
+    /// Only fetch and push mirrors whose destination refs differ from the origin, see `Git::destination_refs`
+    pub changed_only: bool,
+    /// If set, only mirrors with at least one of these labels are synced
+    pub labels: Option<Vec<String>>,
+    /// Rewrite the urls of the mirrors, including their submodules, before syncing
+    pub rewrites: Rewrites,
+    /// Skip mirrors that failed within the cooldown of the cache, updated after the run
+    pub failure_cache: Option<FailureCache>,
+    /// Sync mirrors with a recent failure in the failure cache anyway
+    pub retry_failed: bool,
+    /// Check that the destination hosts can be reached before syncing, see `warm_up`
+    pub warm_up: bool,
+    /// After every push, check that the destination has the refs of the origin, see `verify_push`
+    pub verify: bool,
+    /// Number of loose objects above which a local mirror is garbage collected after syncing, see `Git::maintain`
+    pub gc_threshold: Option<u64>,
+    /// List the refs of HTTP(S) origins with a single request instead of `git ls-remote`, see `Git::origin_refs`
+    pub freshness_probe: bool,
+    /// Lower the number of concurrently synced mirrors on connection failures, see `Concurrency`
+    pub adaptive_concurrency: bool,
+    /// Credentials for all origins on a host by lowercase host, see `origin_credentials`
+    pub origin_credentials: HashMap<String, Credentials>,
+}
 
-    let origin_dir = Path::new(&mirror_dir).join(slugify(origin));
-    debug!("Using origin dir: {0:?}", origin_dir);
+/// Counts connection failures per destination host, shared by all workers of a run
+#[derive(Debug, Clone)]
+struct HostBudget {
+    /// Number of connection failures after which a host is considered down, `None` disables the budget
+    budget: Option<usize>,
+    failures: Arc<Mutex<HashMap<String, usize>>>,
+}
 
-    // Group common setting for al git commands in this closure
-    let git_base_cmd = || {
-        let mut git = Command::new("git");
-        if !log_enabled!(Debug) {
-            git.stdout(Stdio::null());
+impl HostBudget {
+    fn new(budget: Option<usize>) -> HostBudget {
+        HostBudget {
+            budget,
+            failures: Arc::new(Mutex::new(HashMap::new())),
         }
-        debug!("Level {:?}", log_enabled!(Info));
-        git.env("GIT_TERMINAL_PROMPT", "0");
-        return git;
-    };
-
-    git_base_cmd().arg("--version").status().or_else(|e| {
-        Err(format!(
-            "Unable to execute git --version, make sure git is installed. ({})",
-            e
-        ))
-    })?;
+    }
 
-    if origin_dir.is_dir() {
-        info!("Local Update for {}", origin);
+    /// The error for a push to destination if its host is considered down
+    fn check(&self, destination: &str) -> Option<PushError> {
+        let budget = self.budget?;
+        let host = url::host(destination)?;
+        let failures = *self.failures.lock().unwrap().get(&host).unwrap_or(&0);
+        if failures < budget {
+            return None;
+        }
+        Some(
+            format!(
+                "Host down: {} failed {} times with connection errors, not trying",
+                host,
+                failures
+            ).into(),
+        )
+    }
 
-        let mut set_url_cmd = git_base_cmd();
-        set_url_cmd
-            .current_dir(&origin_dir)
-            .args(&["remote", "set-url", "origin"])
-            .arg(origin);
+    /// Count a connection failure for the host of destination
+    fn add_failure(&self, destination: &str) {
+        if self.budget.is_none() {
+            return;
+        }
+        if let Some(host) = url::host(destination) {
+            *self.failures.lock().unwrap().entry(host).or_insert(0) += 1;
+        }
+    }
+}
 
-        trace!("Set url command started: {:?}", set_url_cmd);
+#[derive(Debug)]
+struct ConcurrencyState {
+    limit: usize,
+    active: usize,
+    /// Mirrors finished without connection failures since the limit last changed
+    successes: usize,
+    /// Increased whenever the limit is lowered
+    generation: usize,
+}
 
-        let out = set_url_cmd.status().or_else(|e| {
-            Err(format!(
-                "Unable to execute set url command: {:?} ({})",
-                set_url_cmd,
-                e
-            ))
-        })?;
+/// Limits how many mirrors are synced at the same time, shared by all workers of a run
+///
+/// The limit starts at the number of workers. A mirror whose pushes failed with connection errors
+/// halves it, every `limit` mirrors without such failures raise it by one again, up to the number of
+/// workers. Failures of mirrors started before the limit was last lowered don't lower it again, they
+/// belong to the same spike.
+#[derive(Debug, Clone)]
+struct Concurrency {
+    /// Upper bound of the limit, `None` disables the limit
+    max: Option<usize>,
+    state: Arc<(Mutex<ConcurrencyState>, Condvar)>,
+}
 
-        if !out.success() {
-            return Err(format!(
-                "Set url command ({:?}) failed with exit code: {}",
-                set_url_cmd,
-                out
-            ));
+impl Concurrency {
+    fn new(max: Option<usize>) -> Concurrency {
+        let state = ConcurrencyState {
+            limit: max.unwrap_or(0),
+            active: 0,
+            successes: 0,
+            generation: 0,
+        };
+        Concurrency {
+            max,
+            state: Arc::new((Mutex::new(state), Condvar::new())),
         }
+    }
 
-        let mut remote_update_cmd = git_base_cmd();
-        remote_update_cmd.current_dir(&origin_dir).args(
-            &[
-                "remote",
-                "update",
-            ],
-        );
-
-        trace!("Remote update command started: {:?}", remote_update_cmd);
-
-        let out = remote_update_cmd.status().or_else(|e| {
-            Err(format!(
-                "Unable to execute remote update command: {:?} ({})",
-                remote_update_cmd,
-                e
-            ))
-        })?;
+    /// Wait until another mirror may be synced, returns the generation to pass to `release`
+    fn acquire(&self) -> usize {
+        if self.max.is_none() {
+            return 0;
+        }
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+        while state.active >= state.limit {
+            state = cvar.wait(state).unwrap();
+        }
+        state.active += 1;
+        state.generation
+    }
 
-        if !out.success() {
-            return Err(format!(
-                "Remote update command ({:?}) failed with exit code: {}",
-                remote_update_cmd,
-                out
-            ));
+    /// Finish a mirror started in generation, unreachable if one of its pushes failed with a connection error
+    fn release(&self, generation: usize, unreachable: bool) {
+        let max = match self.max {
+            Some(max) => max,
+            None => return,
+        };
+        let (ref lock, ref cvar) = *self.state;
+        let mut state = lock.lock().unwrap();
+        state.active -= 1;
+        if unreachable {
+            if generation == state.generation && state.limit > 1 {
+                state.limit /= 2;
+                state.generation += 1;
+                warn!("Lowering concurrency to {} after connection failures", state.limit);
+            }
+            state.successes = 0;
+        } else if state.limit < max {
+            state.successes += 1;
+            if state.successes >= state.limit {
+                state.limit += 1;
+                state.successes = 0;
+                info!("Raising concurrency to {}", state.limit);
+            }
         }
+        cvar.notify_all();
+    }
+}
 
-    } else if !origin_dir.exists() {
-        info!("Local Checkout for {}", origin);
+/// Creates the git backend of a mirror from its settings
+type NewBackend = Arc<dyn Fn(Git) -> Box<dyn GitBackend> + Send + Sync>;
 
-        let mut clone_cmd = git_base_cmd();
-        clone_cmd.args(&["clone", "--mirror"]).arg(origin).arg(
-            &origin_dir,
-        );
+/// Register a collector with the default registry
+///
+/// If a collector of the same name was registered by an earlier run in this process, it is kept and
+/// the returned collector isn't registered.
+fn register<C: Collector + Clone + 'static>(collector: C) -> C {
+    if let Err(e) = prometheus::register(Box::new(collector.clone())) {
+        debug!("Not registering metric again ({})", e);
+    }
+    collector
+}
 
-        trace!("Clone command started: {:?}", clone_cmd);
+// Number of destinations per host tried by the warm-up check before the host is considered unusable
+const WARM_UP_CANDIDATES: usize = 3;
 
-        let out = clone_cmd.status().or_else(|e| {
-            Err(format!(
-                "Unable to execute clone command: {:?} ({})",
-                clone_cmd,
-                e
-            ))
-        })?;
+/// Check that every destination host can be reached and authenticated against before syncing
+///
+/// Lists the refs of the first destinations on each host with `git ls-remote`, the host is fine if one
+/// of them can be listed. Several are tried, since a single destination might not exist yet.
+/// Hosts that aren't allowed and local destinations are not checked.
+fn warm_up(v: &[MirrorResult], opts: &MirrorOptions, new_backend: &NewBackend) -> Result<(), String> {
+    let mut candidates: Vec<(String, Vec<&str>)> = Vec::new();
+    for destination in v.iter().filter_map(|m| m.as_ref().ok()).flat_map(|m| m.destinations()) {
+        let host = match url::host(destination) {
+            Some(host) => host,
+            None => continue,
+        };
+        if !is_allowed_destination(destination, &opts.allowed_destination_hosts) {
+            continue;
+        }
+        match candidates.iter().position(|(h, _)| *h == host) {
+            Some(i) => {
+                if candidates[i].1.len() < WARM_UP_CANDIDATES {
+                    candidates[i].1.push(destination)
+                }
+            }
+            None => candidates.push((host, vec![destination])),
+        }
+    }
 
-        if !out.success() {
+    for (host, destinations) in candidates {
+        let git = new_backend(Git {
+            deadline: opts.timeout.map(|t| Instant::now() + t),
+            isolate_credentials: opts.isolate_credentials,
+            ..Default::default()
+        });
+        let mut errors = Vec::new();
+        let reachable = destinations.iter().any(|destination| {
+            match git.destination_refs(destination, Refs::BranchesAndTags) {
+                Ok(_) => {
+                    info!("Warm-up check of {} succeeded using {}", host, destination);
+                    true
+                }
+                Err(e) => {
+                    errors.push(e.message);
+                    false
+                }
+            }
+        });
+        if !reachable {
             return Err(format!(
-                "Clone command ({:?}) failed with exit code: {}",
-                clone_cmd,
-                out
+                "Warm-up check failed, unable to list the refs of any of the first destinations on {}, \
+                 not syncing ({})",
+                host,
+                errors.join("; ")
             ));
         }
-
-    } else {
-        return Err(format!("Local origin dir is a file: {:?}", origin_dir));
     }
+    Ok(())
+}
 
-    info!("Push to destination {}", destination);
+// Number of differing refs mentioned in a verification mismatch
+const VERIFY_SHOWN_REFS: usize = 3;
 
-    let mut push_cmd = git_base_cmd();
-    push_cmd
-        .current_dir(&origin_dir)
-        .args(&["push", "--mirror"])
-        .arg(destination);
+/// Check that destination has the refs of origin after a push, listing both with `git ls-remote`
+///
+/// With branches and tags or all refs, the destination must have exactly the refs of the origin.
+/// With custom refspecs, only the refs they push are compared, see `pushed_refs`.
+fn verify_push(
+    git: &dyn GitBackend,
+    mirror_dir: &str,
+    origin: &str,
+    destination: &str,
+    refs: Refs,
+) -> Result<(), PushError> {
+    let expected = pushed_refs(&git.origin_refs(mirror_dir, origin, refs)?, refs);
+    let actual = git.destination_refs(destination, refs)?;
+    let mut differing: Vec<String> = expected
+        .iter()
+        .filter(|&(name, id)| actual.get(name) != Some(id))
+        .map(|(name, id)| {
+            format!(
+                "{} is {} in the origin and {} in the destination",
+                name,
+                id,
+                actual.get(name).map_or("missing", |id| id)
+            )
+        })
+        .collect();
+    if !matches!(refs, Refs::Custom(_)) {
+        differing.extend(actual.keys().filter(|name| !expected.contains_key(*name)).map(
+            |name| format!("{} is missing in the origin", name),
+        ));
+    }
+    if differing.is_empty() {
+        debug!("Verified {} refs of {}", expected.len(), destination);
+        return Ok(());
+    }
+    let n = differing.len();
+    differing.truncate(VERIFY_SHOWN_REFS);
+    Err(
+        format!(
+            "verification mismatch, {} refs differ: {}{}",
+            n,
+            differing.join(", "),
+            if n > VERIFY_SHOWN_REFS { ", ..." } else { "" }
+        ).into(),
+    )
+}
 
-    trace!("Push  started: {:?}", push_cmd);
+/// Check if the host of a destination is allowed
+fn is_allowed_destination(destination: &str, allowed_hosts: &Option<Vec<String>>) -> bool {
+    match *allowed_hosts {
+        None => true,
+        Some(ref allowed_hosts) => {
+            match url::host(destination) {
+                Some(host) => allowed_hosts.iter().any(|h| h.eq_ignore_ascii_case(&host)),
+                None => false,
+            }
+        }
+    }
+}
 
-    let out = push_cmd.status().or_else(|e| {
-        Err(format!(
-            "Unable to execute push command: {:?} ({})",
-            push_cmd,
-            e
-        ))
-    })?;
+/// The credentials used for the origin of mirror
+///
+/// Credentials of the operator for the host of the origin take precedence, their username defaults to
+/// the `origin_username` of the description. `${VAR}` in descriptions is refused instead of being used
+/// literally, it used to be expanded and likely refers to a secret that belongs in `--origin-credential`.
+fn origin_credentials(
+    mirror: &Mirror,
+    by_host: &HashMap<String, Credentials>,
+) -> Result<Option<Credentials>, String> {
+    let own = mirror.origin_credentials.as_ref();
+    if let Some(c) = url::host(&mirror.origin).and_then(|h| by_host.get(&h)) {
+        return Ok(Some(Credentials {
+            username: c.username.clone().or_else(|| own.and_then(|o| o.username.clone())),
+            password: c.password.clone(),
+        }));
+    }
+    if let Some(c) = own {
+        for (key, value) in [("username", &c.username), ("password", &c.password)] {
+            if value.as_ref().is_some_and(|v| v.contains("${")) {
+                return Err(format!(
+                    "origin_{} contains `${{`, environment variables aren't expanded in descriptions, \
+                     use --origin-credential instead",
+                    key
+                ));
+            }
+        }
+    }
+    Ok(own.cloned())
+}
 
-    if !out.success() {
-        return Err(format!(
-            "Push command ({:?}) failed with exit code: {}",
-            push_cmd,
-            out
-        ));
+pub fn mirror_repo(
+    mirror_dir: String,
+    origin: &str,
+    destination: &str,
+    dry_run: bool,
+) -> Result<u8, String> {
+
+    if dry_run {
+        return Ok(1);
     }
 
-    return Ok(1);
+    let git = Git::default();
+    let origin_dir = git.fetch_repo(&mirror_dir, origin, false)?;
+    git.push_repo(&origin_dir, destination, Refs::All, OnConflict::Fail)
+        .map_err(|e| e.message)?;
+
+    Ok(1)
 }
 
-fn run_sync_task(
-    v: Vec<MirrorResult>,
-    worker_count: usize,
-    mirror_dir: &str,
-    dry_run: bool,
-    label: String,
-) {
+// Upper bounds in seconds of the buckets of git_mirror_duration_seconds
+const DURATION_BUCKETS: [f64; 10] = [1.0, 5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1800.0, 3600.0];
+
+fn run_sync_task(v: Vec<MirrorResult>, opts: &MirrorOptions, label: String, new_backend: &NewBackend) -> RunSummary {
     // Give the work to the worker pool
-    let pool = ThreadPool::new(worker_count);
+    let pool = ThreadPool::new(opts.worker_count);
     let mut n = 0;
 
-    let proj_total = register_counter_vec!("git_mirror_total", "Total projects", &["mirror"])
-        .unwrap();
-    let proj_skip = register_counter_vec!("git_mirror_skip", "Skipped projects", &["mirror"])
-        .unwrap();
-    let proj_fail = register_counter_vec!("git_mirror_fail", "Failed projects", &["mirror"])
-        .unwrap();
-    let proj_ok = register_counter_vec!("git_mirror_ok", "OK projects", &["mirror"]).unwrap();
-    let proj_start = register_gauge_vec!(
-        "git_mirror_project_start",
-        "Start of project mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    ).unwrap();
-    let proj_end = register_gauge_vec!(
-        "git_mirror_project_end",
-        "End of projeect mirror as unix timestamp",
-        &["origin", "destination", "mirror"]
-    ).unwrap();
+    let mut summary = RunSummary {
+        label: label.clone(),
+        ..Default::default()
+    };
+
+    let proj_total = register(CounterVec::new(opts!("git_mirror_total", "Total projects"), &["mirror"]).unwrap());
+    let proj_skip = register(CounterVec::new(opts!("git_mirror_skip", "Skipped projects"), &["mirror"]).unwrap());
+    let proj_fail = register(CounterVec::new(opts!("git_mirror_fail", "Failed projects"), &["mirror"]).unwrap());
+    let proj_ok = register(CounterVec::new(opts!("git_mirror_ok", "OK projects"), &["mirror"]).unwrap());
+    let proj_start = register(
+        GaugeVec::new(
+            opts!(
+                "git_mirror_project_start",
+                "Start of project mirror as unix timestamp"
+            ),
+            &["origin", "destination", "mirror"],
+        ).unwrap(),
+    );
+    let proj_end = register(
+        GaugeVec::new(
+            opts!(
+                "git_mirror_project_end",
+                "End of projeect mirror as unix timestamp"
+            ),
+            &["origin", "destination", "mirror"],
+        ).unwrap(),
+    );
+
+    let proj_duration = register(
+        HistogramVec::new(
+            histogram_opts!(
+                "git_mirror_duration_seconds",
+                "Duration of project mirrors from start to the end of each push",
+                DURATION_BUCKETS.to_vec()
+            ),
+            &["mirror"],
+        ).unwrap(),
+    );
+
+    let host_budget = HostBudget::new(opts.host_failure_budget);
+    let concurrency = Concurrency::new(if opts.adaptive_concurrency {
+        Some(opts.worker_count)
+    } else {
+        None
+    });
+
+    // Origins already queued, so a submodule used by several repositories or a cycle is only mirrored once
+    let mut seen: HashSet<String> = v.iter()
+        .filter_map(|x| x.as_ref().ok())
+        .map(|x| x.origin.clone())
+        .collect();
+    // Mirrors with their submodule depth, submodules found by the workers are appended
+    let mut queue: VecDeque<(MirrorResult, usize)> = v.into_iter().map(|x| (x, 0)).collect();
+    // Every worker reports the submodules of its origin exactly once, right after fetching
+    let (sub_tx, sub_rx) = channel::<Vec<(Mirror, usize)>>();
+    let mut pending = 0;
 
     let (tx, rx) = channel();
-    for x in v {
+    loop {
+        let (x, depth) = match queue.pop_front() {
+            Some(next) => next,
+            None if pending > 0 => {
+                pending -= 1;
+                for (submodule, depth) in sub_rx.recv().unwrap() {
+                    if seen.insert(submodule.origin.clone()) {
+                        info!("Mirroring submodule {} -> {}", submodule.origin, submodule.destination);
+                        queue.push_back((Ok(submodule), depth));
+                    } else {
+                        debug!("Submodule {} is already mirrored", submodule.origin);
+                    }
+                }
+                continue;
+            }
+            None => break,
+        };
         proj_total.with_label_values(&[&label]).inc();
         match x {
             Ok(x) => {
                 let tx = tx.clone();
-                let mirror_dir = mirror_dir.to_owned().clone();
+                let sub_tx = sub_tx.clone();
+                let submodule_depth = opts.submodule_depth;
+                let rewrites = opts.rewrites.clone();
+                let new_backend = new_backend.clone();
+                let changed_only = opts.changed_only;
+                let verify = opts.verify;
+                let gc_threshold = opts.gc_threshold;
+                let freshness_probe = opts.freshness_probe;
+                let credentials = origin_credentials(&x, &opts.origin_credentials);
+                let mirror_dir = opts.mirror_dir.clone();
+                let dry_run = opts.dry_run;
+                let dry_run_refs = opts.dry_run_refs;
+                let smoke = opts.smoke;
+                let timeout = opts.timeout;
+                let on_conflict = opts.on_conflict;
+                let isolate_credentials = opts.isolate_credentials;
+                let unshallow = opts.unshallow;
+                let limits = opts.limits;
+                let keep_locks = opts.keep_locks;
+                let naming_scheme = opts.naming_scheme;
                 let proj_fail = proj_fail.clone();
+                let proj_skip = proj_skip.clone();
                 let proj_ok = proj_ok.clone();
                 let proj_start = proj_start.clone();
                 let proj_end = proj_end.clone();
+                let proj_duration = proj_duration.clone();
                 let label = label.clone();
-                pool.execute(move || {
+                let host_budget = host_budget.clone();
+                let concurrency = concurrency.clone();
+                let mut destinations: Vec<String> = Vec::new();
+                for destination in x.destinations() {
+                    n += 1;
+                    let recent_failure = match opts.failure_cache {
+                        Some(ref cache) if !opts.retry_failed => cache.recent_failure(&x.origin, destination),
+                        _ => None,
+                    };
+                    if let Some(time) = recent_failure {
+                        println!(
+                            "SKIP [{}]: {} -> {} (recent failure at {})",
+                            Local::now(),
+                            x.origin,
+                            destination,
+                            time
+                        );
+                        proj_skip.with_label_values(&[&label]).inc();
+                        tx.send(SyncResult {
+                            origin: x.origin.clone(),
+                            destination: destination.to_owned(),
+                            status: SyncStatus::Skipped("recent failure".to_owned()),
+                            duration: Duration::from_secs(0),
+                        }).unwrap();
+                        continue;
+                    }
+                    if is_allowed_destination(destination, &opts.allowed_destination_hosts) {
+                        destinations.push(destination.to_owned());
+                        continue;
+                    }
                     println!(
-                        "START [{}]: {} -> {}",
+                        "BLOCK [{}]: {} -> {} (destination host not allowed)",
                         Local::now(),
                         x.origin,
-                        x.destination
+                        destination
                     );
-                    proj_start
-                        .with_label_values(&[&x.origin, &x.destination, &label])
-                        .set(Utc::now().timestamp() as f64);
-                    let c = match mirror_repo(mirror_dir, &x.origin, &x.destination, dry_run) {
-                        Ok(c) => {
-                            println!("OK [{}]: {} -> {}", Local::now(), x.origin, x.destination);
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(Utc::now().timestamp() as f64);
-                            proj_ok.with_label_values(&[&label]).inc();
-                            c
+                    proj_fail.with_label_values(&[&label]).inc();
+                    error!(
+                        "Refusing to push {} to {}, host is not in the allowed destination hosts",
+                        x.origin,
+                        destination
+                    );
+                    tx.send(SyncResult {
+                        origin: x.origin.clone(),
+                        destination: destination.to_owned(),
+                        status: SyncStatus::Blocked("destination host not allowed".to_owned()),
+                        duration: Duration::from_secs(0),
+                    }).unwrap();
+                }
+                if destinations.is_empty() {
+                    continue;
+                }
+                pending += 1;
+                pool.execute(move || {
+                    // Waiting for the concurrency limit doesn't count towards the timeout
+                    let generation = concurrency.acquire();
+                    let mut unreachable = false;
+                    // The timeout applies to the whole mirror, including all pushes
+                    let start = Instant::now();
+                    let clone_args = ExtraArgs::for_fetch(x.extra_clone_args.as_deref().unwrap_or_default())
+                        .map_err(|e| format!("Invalid extra_clone_args: {}", e));
+                    let push_args = ExtraArgs::for_push(x.extra_push_args.as_deref().unwrap_or_default())
+                        .map_err(|e| format!("Invalid extra_push_args: {}", e));
+                    let git = Git {
+                        deadline: timeout.map(|t| start + t),
+                        isolate_credentials,
+                        naming_scheme,
+                        unshallow,
+                        limits,
+                        keep_locks,
+                        origin_credentials: credentials.clone().unwrap_or_default(),
+                        config: x.git_config.clone().unwrap_or_default(),
+                        gc_threshold,
+                        clone_args: clone_args.clone().unwrap_or_default(),
+                        push_args: push_args.clone().unwrap_or_default(),
+                        freshness_probe,
+                        renames_branches: x.branch_map.is_some(),
+                    };
+                    // Invalid git config, credentials or extra arguments fail the mirror before any git command runs
+                    let config_valid = validate_config(&git.config)
+                        .and(credentials.map(|_| ()))
+                        .and(clone_args.map(|_| ()))
+                        .and(push_args.map(|_| ()))
+                        .and(if x.refspecs.is_some() && x.branch_map.is_some() {
+                            Err("branch_map can't be combined with refspecs, rename in the refspecs".to_owned())
+                        } else {
+                            Ok(())
+                        });
+                    let git = new_backend(git);
+
+                    for destination in &destinations {
+                        println!("START [{}]: {} -> {}", Local::now(), x.origin, destination);
+                        proj_start
+                            .with_label_values(&[&x.origin, destination, &label])
+                            .set(Utc::now().timestamp() as f64);
+                    }
+
+                    // Renaming branches needs refspecs
+                    let branch_refspecs = x.branch_map.as_ref().map(|m| branch_map_refspecs(m, x.all_refs));
+                    let refs = match x.refspecs.as_ref().or(branch_refspecs.as_ref()) {
+                        Some(refspecs) => Refs::Custom(refspecs),
+                        None if x.all_refs => Refs::All,
+                        None => Refs::BranchesAndTags,
+                    };
+
+                    // Destinations that already have the refs of the origin. Custom refspecs may rename
+                    // refs, so those are always synced. A destination that can't be listed is synced as well.
+                    let mut up_to_date = vec![false; destinations.len()];
+                    if changed_only && !dry_run && !smoke && config_valid.is_ok() &&
+                        !matches!(refs, Refs::Custom(_))
+                    {
+                        match git.origin_refs(&mirror_dir, &x.origin, refs) {
+                            Ok(origin_refs) => {
+                                for (i, destination) in destinations.iter().enumerate() {
+                                    if host_budget.check(destination).is_some() {
+                                        continue;
+                                    }
+                                    match git.destination_refs(destination, refs) {
+                                        Ok(destination_refs) => {
+                                            up_to_date[i] = destination_refs == origin_refs
+                                        }
+                                        Err(e) => {
+                                            debug!("Unable to list refs of {} ({})", destination, e.message)
+                                        }
+                                    }
+                                }
+                            }
+                            Err(e) => warn!("Unable to list refs of {} ({})", x.origin, e),
                         }
-                        Err(e) => {
-                            println!(
-                                "FAIL [{}]: {} -> {} ({})",
-                                Local::now(),
-                                x.origin,
-                                x.destination,
-                                e
-                            );
-                            proj_end
-                                .with_label_values(&[&x.origin, &x.destination, &label])
-                                .set(Utc::now().timestamp() as f64);
-                            proj_fail.with_label_values(&[&label]).inc();
-                            error!(
-                                "Unable to sync repo {} -> {} ({})",
-                                x.origin,
-                                x.destination,
-                                e
-                            );
-                            0
+                    }
+
+                    // Fetch the origin only once for all destinations
+                    let fetched = match refs {
+                        Refs::Custom(refspecs) => validate_refspecs(refspecs),
+                        _ => Ok(()),
+                    }.and_then(|_| config_valid.clone()).and_then(|_| if dry_run && !dry_run_refs {
+                        Ok(None)
+                    } else if destinations.iter().zip(&up_to_date).all(|(d, &up_to_date)| {
+                        up_to_date || host_budget.check(d).is_some()
+                    })
+                    {
+                        // No need to fetch, all destinations are up to date or refused by the host budget
+                        Ok(None)
+                    } else if smoke {
+                        git.fetch_smoke(&mirror_dir, &x.origin).map(Some)
+                    } else {
+                        let origin_dir = git.fetch_repo(&mirror_dir, &x.origin, x.partial)?;
+                        git.check_limits(&origin_dir, refs)?;
+                        Ok(Some(origin_dir))
+                    });
+
+                    let mut submodules = Vec::new();
+                    if x.recurse_submodules && depth < submodule_depth {
+                        // The local mirror of an up to date origin is current from an earlier run
+                        let origin_dir = match fetched {
+                            Ok(Some(ref origin_dir)) => Some(origin_dir.clone()),
+                            Ok(None) if up_to_date.iter().any(|&u| u) => {
+                                Some(git.origin_dir(&mirror_dir, &x.origin))
+                                    .filter(|d| d.is_dir())
+                            }
+                            _ => None,
+                        };
+                        if let Some(ref origin_dir) = origin_dir {
+                            match git.submodule_urls(origin_dir) {
+                                Ok(urls) => {
+                                    submodules = urls.iter()
+                                        .map(|url| (rewrites.apply(x.submodule(url)), depth + 1))
+                                        .collect()
+                                }
+                                Err(e) => warn!("Unable to list submodules of {} ({})", x.origin, e),
+                            }
                         }
-                    };
-                    tx.send(c).unwrap();
+                    }
+                    sub_tx.send(submodules).unwrap();
+
+                    // A failure on one destination doesn't prevent pushing to the others
+                    for (destination, &up_to_date) in destinations.iter().zip(&up_to_date) {
+                        let res = match (host_budget.check(destination), fetched.as_ref()) {
+                            _ if up_to_date => Ok(Push::UpToDate),
+                            (Some(e), _) => Err(e),
+                            (None, Ok(Some(smoke_dir))) if smoke => {
+                                git.push_smoke(smoke_dir, destination)
+                            }
+                            (None, Ok(Some(origin_dir))) if dry_run => {
+                                git.ref_changes(origin_dir, destination, refs).map(|changes| {
+                                    if changes.is_empty() {
+                                        println!(
+                                            "REF [{}]: {} -> {} (no changes)",
+                                            Local::now(),
+                                            x.origin,
+                                            destination
+                                        );
+                                    }
+                                    for change in changes {
+                                        println!(
+                                            "REF [{}]: {} -> {} {}",
+                                            Local::now(),
+                                            x.origin,
+                                            destination,
+                                            change
+                                        );
+                                    }
+                                    Push::Done
+                                })
+                            }
+                            (None, Ok(Some(origin_dir))) => {
+                                if x.partial {
+                                    git.check_partial_support(destination);
+                                }
+                                git.push_repo(origin_dir, destination, refs, on_conflict).and_then(|push| {
+                                    match push {
+                                        Push::Done if verify => {
+                                            verify_push(&*git, &mirror_dir, &x.origin, destination, refs)
+                                                .map(|_| push)
+                                        }
+                                        push => Ok(push),
+                                    }
+                                })
+                            }
+                            (None, Ok(None)) => Ok(Push::Done),
+                            (None, Err(e)) => Err(e.clone().into()),
+                        };
+                        if let Err(ref e) = res {
+                            if e.unreachable {
+                                host_budget.add_failure(destination);
+                                unreachable = true;
+                            }
+                        }
+                        let res = res.map_err(|e| e.message);
+                        let status = match res {
+                            Ok(Push::Skipped(reason)) => {
+                                println!(
+                                    "SKIP [{}]: {} -> {} ({})",
+                                    Local::now(),
+                                    x.origin,
+                                    destination,
+                                    reason
+                                );
+                                proj_skip.with_label_values(&[&label]).inc();
+                                SyncStatus::Skipped(reason)
+                            }
+                            Ok(Push::UpToDate) => {
+                                println!(
+                                    "UP-TO-DATE [{}]: {} -> {}",
+                                    Local::now(),
+                                    x.origin,
+                                    destination
+                                );
+                                proj_ok.with_label_values(&[&label]).inc();
+                                SyncStatus::UpToDate
+                            }
+                            Ok(Push::Done) => {
+                                println!("OK [{}]: {} -> {}", Local::now(), x.origin, destination);
+                                proj_ok.with_label_values(&[&label]).inc();
+                                SyncStatus::Ok
+                            }
+                            Err(e) => {
+                                println!(
+                                    "FAIL [{}]: {} -> {} ({})",
+                                    Local::now(),
+                                    x.origin,
+                                    destination,
+                                    e
+                                );
+                                proj_fail.with_label_values(&[&label]).inc();
+                                error!(
+                                    "Unable to sync repo {} -> {} ({})",
+                                    x.origin,
+                                    destination,
+                                    e
+                                );
+                                SyncStatus::Failed(e)
+                            }
+                        };
+                        proj_end
+                            .with_label_values(&[&x.origin, destination, &label])
+                            .set(Utc::now().timestamp() as f64);
+                        let duration = start.elapsed();
+                        proj_duration
+                            .with_label_values(&[&label])
+                            .observe(duration.as_secs_f64());
+                        tx.send(SyncResult {
+                            origin: x.origin.clone(),
+                            destination: destination.clone(),
+                            status,
+                            duration,
+                        }).unwrap();
+                    }
+
+                    if let Ok(Some(ref dir)) = fetched {
+                        if smoke {
+                            git.remove_smoke(dir);
+                        } else if let Err(e) = git.maintain(dir) {
+                            warn!("Unable to gc the local mirror of {} ({})", x.origin, e);
+                        }
+                    }
+                    concurrency.release(generation, unreachable);
                 });
-                n += 1;
             }
             Err(e) => {
                 proj_skip.with_label_values(&[&label]).inc();
                 warn!("Skipping: {:?}", e);
+                match e {
+                    MirrorError::Skip(_) => summary.add_provider_skip(),
+                    MirrorError::Description(url, e) => {
+                        summary.add_provider_error(format!("Invalid description of {} ({})", url, e))
+                    }
+                }
             }
         };
     }
 
+    for result in rx.iter().take(n) {
+        summary.add_result(result);
+    }
+    // Workers may still be cleaning up after sending their last result
+    pool.join();
+
     println!(
         "DONE [{2}]: {0}/{1}",
-        rx.iter().take(n).fold(0, |a, b| a + b),
+        summary
+            .results
+            .iter()
+            .filter(|r| matches!(r.status, SyncStatus::Ok | SyncStatus::UpToDate))
+            .count(),
         n,
         Local::now()
     );
 
+    summary
 }
 
 
-pub fn do_mirror(
-    provider: &Provider,
-    worker_count: usize,
-    mirror_dir: &str,
-    dry_run: bool,
-    metrics_file: Option<String>,
-) -> Result<(), String> {
-    let start_time = register_gauge_vec!(
-        "git_mirror_start_time",
-        "Start time of the sync as unix timestamp",
-        &["mirror"]
-    ).unwrap();
-    let end_time = register_gauge_vec!(
-        "git_mirror_end_time",
-        "End time of the sync as unix timestamp",
-        &["mirror"]
-    ).unwrap();
+pub fn do_mirror(provider: &dyn Provider, opts: &MirrorOptions) -> Result<RunSummary, String> {
+    do_mirror_with(provider, opts, &(Arc::new(|git| Box::new(git) as Box<dyn GitBackend>) as NewBackend))
+}
+
+/// Like `do_mirror`, with the git operations of every mirror done by the backend new_backend returns
+fn do_mirror_with(provider: &dyn Provider, opts: &MirrorOptions, new_backend: &NewBackend) -> Result<RunSummary, String> {
+    let mirror_dir = &opts.mirror_dir;
+
+    let start_time = register(
+        GaugeVec::new(
+            opts!(
+                "git_mirror_start_time",
+                "Start time of the sync as unix timestamp"
+            ),
+            &["mirror"],
+        ).unwrap(),
+    );
+    let end_time = register(
+        GaugeVec::new(
+            opts!(
+                "git_mirror_end_time",
+                "End time of the sync as unix timestamp"
+            ),
+            &["mirror"],
+        ).unwrap(),
+    );
 
     // Make sure the mirror directory exists
     trace!("Create mirror directory at {:?}", mirror_dir);
@@ -334,27 +929,54 @@ pub fn do_mirror(
     trace!("Aquired lockfile: {:?}", &lockfile);
 
     // Get the list of repos to sync from gitlabsss
-    let v = provider.get_mirror_repos().map_err(|e| -> String {
+    let mut v = provider.get_mirror_repos().map_err(|e| -> String {
         format!("Unable to get mirror repos ({})", e)
     })?;
 
+    if let Some(ref labels) = opts.labels {
+        let total = v.len();
+        v = filter_labels(v, labels);
+        debug!("Selected {} of {} projects by labels {:?}", v.len(), total, labels);
+    }
+
+    v = v.into_iter().map(|m| m.map(|m| opts.rewrites.apply(m))).collect();
+
+    if let Some(key) = opts.sort {
+        sort_mirrors(&mut v, key);
+    }
+
+    // Dry runs without ref changes don't contact the destinations
+    if opts.warm_up && (!opts.dry_run || opts.dry_run_refs) {
+        warm_up(&v, opts, new_backend)?;
+    }
+
     start_time.with_label_values(&[&provider.get_label()]).set(
         Utc::now().timestamp() as f64,
     );
 
-    run_sync_task(v, worker_count, mirror_dir, dry_run, provider.get_label());
+    let summary = run_sync_task(v, opts, provider.get_label(), new_backend);
 
     end_time.with_label_values(&[&provider.get_label()]).set(
         Utc::now().timestamp() as
             f64,
     );
 
-    match metrics_file {
-        Some(f) => write_metrics(&f),
+    // Dry runs don't show whether a mirror works
+    match opts.failure_cache {
+        Some(ref cache) if !opts.dry_run => {
+            if let Err(e) = cache.save(&summary.results) {
+                warn!("{}", e);
+            }
+        }
+        _ => {}
+    }
+
+    match opts.metrics_file {
+        Some(ref f) => write_metrics(f),
         None => trace!("Skipping merics file creation"),
     };
 
-    Ok(())
+    Ok(summary)
 }
 
 fn write_metrics(f: &str) {
@@ -365,3 +987,87 @@ fn write_metrics(f: &str) {
 }
 
 pub mod provider;
+mod git;
+mod url;
+mod layout;
+pub use layout::{Flatten, NamingScheme};
+use git::{branch_map_refspecs, pushed_refs, validate_config, ExtraArgs, validate_refspecs, Git, GitBackend, Push, PushError,
+          Refs};
+mod summary;
+pub use summary::{RunSummary, SyncResult, SyncStatus};
+mod report;
+pub use report::{ReportFormatter, TextFormatter, JsonFormatter, JUnitFormatter};
+mod expand;
+pub use expand::expand_env;
+mod rewrite;
+pub use rewrite::{Rewrite, Rewrites};
+mod runlog;
+pub use runlog::{RunLog, RunRecord};
+mod failures;
+pub use failures::FailureCache;
+mod probe;
+mod inventory;
+pub use inventory::{config_hash, Inventory, InventoryEntry};
+mod explain;
+pub use explain::{explain, Decision, Explanation};
+#[cfg(feature = "test-util")]
+pub mod testutil;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mirror(origin: &str, credentials: Option<Credentials>) -> Mirror {
+        Mirror {
+            origin: origin.to_owned(),
+            destination: "git@example.com:mirror/repo.git".to_owned(),
+            extra_destinations: Vec::new(),
+            all_refs: false,
+            refspecs: None,
+            partial: false,
+            origin_credentials: credentials,
+            recurse_submodules: false,
+            labels: Vec::new(),
+            git_config: None,
+            extra_clone_args: None,
+            extra_push_args: None,
+            branch_map: None,
+        }
+    }
+
+    fn credentials(username: Option<&str>, password: Option<&str>) -> Credentials {
+        Credentials {
+            username: username.map(|u| u.to_owned()),
+            password: password.map(|p| p.to_owned()),
+        }
+    }
+
+    #[test]
+    fn origin_credentials_of_description_are_not_expanded() {
+        let by_host = HashMap::new();
+        let m = mirror("https://git.example.com/repo.git", Some(credentials(Some("ci"), Some("${HOME}"))));
+        let e = origin_credentials(&m, &by_host).unwrap_err();
+        assert!(e.contains("origin_password"), "{}", e);
+
+        let m = mirror("https://git.example.com/repo.git", Some(credentials(Some("ci"), Some("s$cret"))));
+        assert_eq!(
+            origin_credentials(&m, &by_host),
+            Ok(Some(credentials(Some("ci"), Some("s$cret"))))
+        );
+    }
+
+    #[test]
+    fn origin_credentials_of_operator_take_precedence() {
+        let mut by_host = HashMap::new();
+        by_host.insert("git.example.com".to_owned(), credentials(None, Some("operator")));
+
+        // The username of the description is kept, its password isn't used
+        let m = mirror("https://Git.Example.com/repo.git", Some(credentials(Some("ci"), Some("${TOKEN}"))));
+        assert_eq!(
+            origin_credentials(&m, &by_host),
+            Ok(Some(credentials(Some("ci"), Some("operator"))))
+        );
+        let m = mirror("https://other.example.com/repo.git", None);
+        assert_eq!(origin_credentials(&m, &by_host), Ok(None));
+    }
+}
@@ -4,8 +4,12 @@
  * SPDX-License-Identifier:     MIT
  */
 
-use std::env;
 use std::cmp;
+use std::io;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::collections::HashMap;
+use std::env;
 
 // Used for error and debug logging
 #[macro_use]
@@ -16,20 +20,75 @@ extern crate stderrlog;
 // Used to do command line parsing
 #[macro_use]
 extern crate clap;
-use clap::{Arg, App};
+use clap::{Arg, App, ArgMatches, SubCommand};
 
 // Load the real functionality
 extern crate git_mirror;
-use git_mirror::do_mirror;
-use git_mirror::provider::{GitLab, GitHub};
+use git_mirror::{config_hash, do_mirror, expand_env, explain, FailureCache, Flatten, Inventory, Limits, MirrorOptions,
+                 NamingScheme, OnConflict, Rewrite, Rewrites, RunLog, RunRecord, RunSummary};
+use git_mirror::{ReportFormatter, TextFormatter, JsonFormatter, JUnitFormatter};
+use git_mirror::provider::{description_template, Credentials, GitLab, GitHub, StdinProvider, ManifestProvider,
+                           ProviderFetchMode, Scope};
+use git_mirror::provider::{TokenResolver, EnvResolver, CommandResolver, Provider, SortKey, ApiCache};
 
 use std::process::{exit};
+use std::fs::File;
+use std::path::Path;
+
+// Time handling
+extern crate chrono;
+use chrono::{DateTime, Utc};
+
+arg_enum!{
+    #[derive(Debug)]
+    enum Naming {
+      Slug,
+      Namespace
+    }
+}
+
+arg_enum!{
+    #[derive(Debug)]
+    enum Conflicts {
+      Fail,
+      ForceOverwrite,
+      Skip
+    }
+}
+
+arg_enum!{
+    #[derive(Debug)]
+    enum Sorting {
+      Origin,
+      Destination,
+      Name
+    }
+}
 
 arg_enum!{
     #[derive(Debug)]
+    enum ReportFormats {
+      Text,
+      Json,
+      JUnit
+    }
+}
+
+arg_enum!{
+    #[derive(Debug)]
+    enum InventoryFormats {
+      Json,
+      Csv
+    }
+}
+
+arg_enum!{
+    #[derive(Debug, PartialEq)]
     enum Providers {
       GitLab,
-      GitHub
+      GitHub,
+      Stdin,
+      Manifest
     }
 }
 
@@ -41,9 +100,126 @@ fn main() {
             Arg::with_name("url")
                 .short("u")
                 .long("url")
-                .help("URL of the instance to get repositories from")
+                .help(
+                    "URL of the instance to get repositories from, \
+                       for the Manifest provider the url of the repository containing the manifest",
+                )
                 .default_value_if("provider", Some("GitLab"), "https://gitlab.com")
-                .default_value_if("provider", Some("GitHub"), "https://api.github.com"),
+                .default_value_if("provider", Some("GitHub"), "https://api.github.com")
+                .required_if("provider", "Manifest"),
+        )
+        .arg(
+            Arg::with_name("max-redirects")
+                .long("max-redirects")
+                .help(
+                    "Maximum number of redirects followed for a single GitLab API request, \
+                       the private token is only sent along to the same host and port",
+                )
+                .takes_value(true)
+                .default_value("5"),
+        )
+        .arg(
+            Arg::with_name("base-path")
+                .long("base-path")
+                .help(
+                    "Path of a GitLab instance served below the root of its host, e.g. /gitlab, \
+                       the API is expected at <url><base-path>/api/v4",
+                )
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("keyset-pagination").long("keyset-pagination").help(
+            "List GitLab projects with keyset pagination, which doesn't miss or repeat projects \
+             changed during the listing, falls back to offset pagination if unavailable",
+        ))
+        .arg(
+            Arg::with_name("flatten-into")
+                .long("flatten-into")
+                .help(
+                    "Push GitLab projects to <prefix><flattened path>.git instead of their own project, \
+                       e.g. git@github.com:mirror-org/ pushes team/sub/repo to git@github.com:mirror-org/team-sub-repo.git",
+                )
+                .value_name("prefix")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("flatten-separator")
+                .long("flatten-separator")
+                .help("Joins the components of a project path with --flatten-into, e.g. - or __")
+                .takes_value(true)
+                .default_value("-")
+                .validator(|v| if v.contains('/') {
+                    Err("The separator can't contain /".to_owned())
+                } else {
+                    Ok(())
+                }),
+        )
+        .arg(
+            Arg::with_name("flatten-disambiguate")
+                .long("flatten-disambiguate")
+                .help(
+                    "Append the hash of the project path to a flattened name that is already taken, \
+                       instead of only warning about it",
+                ),
+        )
+        .arg(
+            Arg::with_name("run-log")
+                .long("run-log")
+                .help(
+                    "Append a JSON line with the time, provider, counts and duration of every run to this file, \
+                       shared safely by concurrent runs",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("run-log-max-bytes")
+                .long("run-log-max-bytes")
+                .help("Size from which the run log is moved to <run-log>.1 and a new one is started")
+                .takes_value(true)
+                .default_value("10485760"),
+        )
+        .arg(
+            Arg::with_name("failure-cache")
+                .long("failure-cache")
+                .help(
+                    "Remember failed mirrors in this file and skip them in later runs \
+                       until --failure-cooldown has passed",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("failure-cooldown")
+                .long("failure-cooldown")
+                .help("Seconds a failed mirror in the failure cache is skipped")
+                .takes_value(true)
+                .default_value("86400"),
+        )
+        .arg(Arg::with_name("retry-failed").long("retry-failed").help(
+            "Sync mirrors with a recent failure in the failure cache anyway",
+        ))
+        .arg(
+            Arg::with_name("disable-file")
+                .long("disable-file")
+                .help(
+                    "Exit with code 3 right at the start, without contacting any provider or remote, \
+                       if this file exists",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("manifest-path")
+                .long("manifest-path")
+                .help("Path of the manifest file inside the manifest repository")
+                .takes_value(true)
+                .default_value("mirrors.yaml"),
+        )
+        .arg(
+            Arg::with_name("manifest-revision")
+                .long("manifest-revision")
+                .help(
+                    "Branch or tag of the manifest repository to read the manifest from, \
+                       defaults to its default branch",
+                )
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("group")
@@ -51,7 +227,31 @@ fn main() {
                 .long("group")
                 .help("Name of the group to check for repositories to sync")
                 .takes_value(true)
-                .required(true),
+                .required_if("provider", "GitHub")
+                .conflicts_with("instance"),
+        )
+        .arg(Arg::with_name("instance").long("instance").help(
+            "Check all projects of the GitLab instance instead of a group, \
+             requires an admin token",
+        ))
+        .arg(
+            Arg::with_name("api-cache")
+                .long("api-cache")
+                .help(
+                    "File to cache GitLab listing pages with their ETag in, \
+                       unchanged pages are not transferred again on the next run",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("since")
+                .long("since")
+                .help(
+                    "Only mirror GitLab projects with activity since this RFC 3339 time, \
+                       e.g. `2018-01-31T00:00:00Z`",
+                )
+                .takes_value(true)
+                .validator(|v| parse_since(&v).map(|_| ())),
         )
         .arg(
             Arg::with_name("mirror-dir")
@@ -60,14 +260,47 @@ fn main() {
                 .help("Directory where the local clones are stored")
                 .default_value("./mirror-dir"),
         )
+        .arg(
+            Arg::with_name("naming-scheme")
+                .long("naming-scheme")
+                .help(
+                    "How the local clones are named, Namespace creates \
+                       <host>/<namespace>/<repo>.git directories",
+                )
+                .takes_value(true)
+                .possible_values(&Naming::variants())
+                .default_value("Slug"),
+        )
         .arg(Arg::with_name("v").short("v").multiple(true).help(
             "Verbosity level",
         ))
         .arg(Arg::with_name("http").long("https").help(
             "Use http(s) instead of SSH to sync the GitLab repository",
         ))
-        .arg(Arg::with_name("dry-run").long("dry-run").help(
-            "Only print what to do without actually running any git commands.",
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .help(
+                    "Only print what to do without actually running any git commands. \
+                       With --dry-run=refs the origins are fetched and the refs every push \
+                       would create, update or delete are printed, nothing is pushed.",
+                )
+                .takes_value(true)
+                .min_values(0)
+                .max_values(1)
+                .require_equals(true)
+                .possible_values(&["refs"]),
+        )
+        .arg(Arg::with_name("smoke").long("smoke").help(
+            "Smoke test: only fetch the default branch of every origin with depth 1 and check \
+             that it could be pushed using `git push --dry-run`. Nothing is mirrored.",
+        ))
+        .arg(Arg::with_name("count").long("count").help(
+            "Only print the number of projects that would be mirrored, followed by a breakdown. \
+             GitLab descriptions are not fully parsed in this mode.",
+        ))
+        .arg(Arg::with_name("explain").long("explain").help(
+            "Only print for every project whether it would be mirrored and why, without running git",
         ))
         .arg(
             Arg::with_name("worker-count")
@@ -76,6 +309,217 @@ fn main() {
                 .help("Number of concurrent mirror jobs")
                 .default_value("1"),
         )
+        .arg(
+            Arg::with_name("timeout-per-mirror")
+                .long("timeout-per-mirror")
+                .help(
+                    "Time in seconds after which the git commands of a mirror are killed, \
+                       0 disables the timeout",
+                )
+                .default_value("600"),
+        )
+        .arg(
+            Arg::with_name("allowed-destination-host")
+                .long("allowed-destination-host")
+                .help(
+                    "Only push to destinations on this host, can be given multiple times. \
+                       All hosts are allowed if not given.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("gc-threshold")
+                .long("gc-threshold")
+                .help(
+                    "Run git gc on a local mirror after syncing once it has more loose objects than this",
+                )
+                .takes_value(true)
+                .default_value("10000"),
+        )
+        .arg(Arg::with_name("no-gc").long("no-gc").help(
+            "Never run git gc on the local mirrors",
+        ))
+        .arg(
+            Arg::with_name("host-failure-budget")
+                .long("host-failure-budget")
+                .help(
+                    "Number of connection failures after which the remaining mirrors to \
+                       a destination host fail without trying",
+                )
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("adaptive-concurrency").long("adaptive-concurrency").help(
+            "Halve the number of concurrently synced mirrors when pushes fail with connection \
+             errors and raise it by one again after as many mirrors without, at most -c",
+        ))
+        .arg(
+            Arg::with_name("submodule-depth")
+                .long("submodule-depth")
+                .help(
+                    "How many levels of nested submodules are mirrored for projects \
+                       with recurse_submodules in their description",
+                )
+                .takes_value(true)
+                .default_value("3"),
+        )
+        .arg(
+            Arg::with_name("label")
+                .long("label")
+                .help(
+                    "Only sync mirrors with this label in their description, \
+                       can be given multiple times to sync mirrors with any of the labels",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("exclude-project-id")
+                .long("exclude-project-id")
+                .help(
+                    "Never mirror the GitLab project with this id, even if its description \
+                       configures a mirror, can be given multiple times",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| format!("{}", e))),
+        )
+        .arg(
+            Arg::with_name("exclude-path")
+                .long("exclude-path")
+                .help(
+                    "Never mirror the GitLab project with this path, e.g. group/project, even if its \
+                       description configures a mirror, can be given multiple times",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("rewrite-origin")
+                .long("rewrite-origin")
+                .help(
+                    "Replace the first match of the regex in the origin urls, after the provider \
+                       resolved them. Can be given multiple times, the rules are applied in order. \
+                       $1 in the replacement is the first group of the regex",
+                )
+                .value_names(&["regex", "replacement"])
+                .multiple(true)
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("rewrite-destination")
+                .long("rewrite-destination")
+                .help("Like --rewrite-origin, but for the destination urls")
+                .value_names(&["regex", "replacement"])
+                .multiple(true)
+                .number_of_values(2),
+        )
+        .arg(
+            Arg::with_name("origin-credential")
+                .long("origin-credential")
+                .help(
+                    "HTTP basic auth for the origins on a host, e.g. `git.example.com=ci:UPSTREAM_PASSWORD` \
+                       reads the password from the environment variable UPSTREAM_PASSWORD. The username is \
+                       optional and defaults to the origin_username of the description. Can be given \
+                       multiple times, overrides the credentials of descriptions.",
+                )
+                .value_name("host=[username:]env")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| parse_origin_credential(&v).map(|_| ())),
+        )
+        .arg(Arg::with_name("no-warm-up").long("no-warm-up").help(
+            "Don't check that every destination host can be reached with git ls-remote before \
+             syncing, e.g. if some destinations are only reachable during the sync",
+        ))
+        .arg(Arg::with_name("verify").long("verify").help(
+            "After every push, list the refs of origin and destination with git ls-remote and \
+             fail the mirror if they don't match",
+        ))
+        .arg(Arg::with_name("freshness-probe").long("freshness-probe").help(
+            "With --changed-only and --verify, list the refs of HTTP(S) origins with a single \
+             request for their ref advertisement instead of running git ls-remote",
+        ))
+        .arg(Arg::with_name("changed-only").long("changed-only").help(
+            "Compare the refs of origin and destination with git ls-remote first and \
+             only fetch and push mirrors whose refs differ",
+        ))
+        .arg(Arg::with_name("isolate-credentials").long("isolate-credentials").help(
+            "Only use credentials contained in the urls or the SSH setup, \
+             ignoring git credential helpers, askpass programs and the system git config",
+        ))
+        .arg(Arg::with_name("all-refs").long("all-refs").help(
+            "Push all refs of GitLab projects, including refs/merge-requests/* and refs/pipelines/*. \
+             By default only branches and tags are pushed.",
+        ))
+        .arg(Arg::with_name("unshallow").long("unshallow").help(
+            "Try to fetch the missing history if an origin is a shallow clone \
+             instead of failing the mirror",
+        ))
+        .arg(Arg::with_name("no-lock-cleanup").long("no-lock-cleanup").help(
+            "Don't remove lock files left behind in the local mirrors by interrupted git commands",
+        ))
+        .arg(
+            Arg::with_name("max-refs")
+                .long("max-refs")
+                .help("Don't push mirrors with more refs than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max-pack-bytes")
+                .long("max-pack-bytes")
+                .help("Don't push mirrors whose objects are larger than this number of bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("on-conflict")
+                .long("on-conflict")
+                .help(
+                    "What to do if the destination refuses the push because \
+                       it has unrelated history",
+                )
+                .takes_value(true)
+                .possible_values(&Conflicts::variants())
+                .default_value("Fail"),
+        )
+        .arg(
+            Arg::with_name("fetch-workers")
+                .long("fetch-workers")
+                .help(
+                    "Number of concurrent API page requests (GitLab only). \
+                       The order of the mirrors is not preserved if larger than 1.",
+                )
+                .default_value("1"),
+        )
+        .arg(
+            Arg::with_name("concurrency-per-provider")
+                .long("concurrency-per-provider")
+                .help(
+                    "Number of concurrent API page requests for a single provider, \
+                       e.g. `GitLab=8`. Overrides --fetch-workers, can be given multiple times.",
+                )
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .validator(|v| parse_provider_concurrency(&v).map(|_| ())),
+        )
+        .arg(
+            Arg::with_name("sort")
+                .long("sort")
+                .help("Sort the mirrors to get a deterministic order instead of the provider order")
+                .takes_value(true)
+                .possible_values(&Sorting::variants()),
+        )
+        .arg(
+            Arg::with_name("sort-by-destination")
+                .long("sort-by-destination")
+                .help("Same as --sort Destination")
+                .conflicts_with("sort"),
+        )
         .arg(
             Arg::with_name("provider")
                 .short("p")
@@ -85,6 +529,29 @@ fn main() {
                 .possible_values(&Providers::variants())
                 .default_value("GitLab"),
         )
+        .arg(
+            Arg::with_name("report-format")
+                .long("report-format")
+                .help("Format of the report printed at the end of the run")
+                .takes_value(true)
+                .possible_values(&ReportFormats::variants())
+                .default_value("Text"),
+        )
+        .arg(
+            Arg::with_name("report-file")
+                .long("report-file")
+                .help(
+                    "Write the report to this file instead of stdout. The progress of the mirrors is \
+                       printed to stdout, so only the file is valid JSON or JUnit XML",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("summary-file")
+                .long("summary-file")
+                .help("Location where to store a JSON summary of the run")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("metrics-file")
                 .long("metrics-file")
@@ -94,9 +561,55 @@ fn main() {
                 )
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("token-command")
+                .long("token-command")
+                .help(
+                    "Command printing the token to access the provider API, e.g. \
+                       `vault read -field=token secret/gitlab`. It is run for every API request \
+                       instead of reading the token from the environment.",
+                )
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("describe")
+                .about("Print a project description template for mirroring origin")
+                .arg(
+                    Arg::with_name("origin")
+                        .long("origin")
+                        .help("Source repository to mirror from")
+                        .takes_value(true)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("inventory")
+                .about(
+                    "Write a list of all mirrors the provider would sync, with the metadata of their projects, \
+                     without running git. Use the same arguments as for the sync before the subcommand.",
+                )
+                .arg(
+                    Arg::with_name("format")
+                        .long("format")
+                        .help("Format of the inventory")
+                        .takes_value(true)
+                        .possible_values(&InventoryFormats::variants())
+                        .default_value("Json"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("File the inventory is written to instead of stdout")
+                        .takes_value(true),
+                ),
+        )
         .after_help(
             "ENVIRONMENT:\n    GITLAB_PRIVATE_TOKEN    \
-                     Private token or Personal access token to access the GitLab API",
+                     Private token or Personal access token to access the GitLab API\n    \
+             GITHUB_PRIVATE_TOKEN    Personal access token to access the GitHub API\n\n\
+             STDIN:\n    With `--provider Stdin` one `<origin> <destination>` pair is read \
+                     per line.\n    Empty lines and lines starting with `#` are ignored.",
         )
         .get_matches();
 
@@ -107,52 +620,401 @@ fn main() {
         .init()
         .unwrap();
 
-    let gitlab_private_token = env::var("GITLAB_PRIVATE_TOKEN").ok();
+    // Emergency brake for operators, checked before anything else happens
+    if let Some(disable_file) = m.value_of("disable-file") {
+        if Path::new(disable_file).exists() {
+            error!("Mirroring is disabled: {} exists, remove it to enable mirroring again", disable_file);
+            exit(3);
+        }
+    }
+
+    if let Some(d) = m.subcommand_matches("describe") {
+        let origin = value_t_or_exit!(d.value_of("origin"), String);
+        match description_template(&origin) {
+            Ok(desc) => print!("{}", desc),
+            Err(e) => {
+                error!("Error occured: {}", e);
+                exit(2);
+            }
+        }
+        return;
+    }
 
     // Make sense of the arguments
     let mirror_dir = value_t_or_exit!(m.value_of("mirror-dir"), String);
     debug!("Using mirror directory: {}", mirror_dir);
-    let gitlab_url = value_t_or_exit!(m.value_of("url"), String);
+    let naming_scheme = match value_t_or_exit!(m.value_of("naming-scheme"), Naming) {
+        Naming::Slug => NamingScheme::Slug,
+        Naming::Namespace => NamingScheme::Namespace,
+    };
+    debug!("Naming scheme: {:?}", naming_scheme);
+    let provider = value_t_or_exit!(m.value_of("provider"), Providers);
+    debug!("Using provider: {:?}", provider);
+    let instance = m.is_present("instance");
+    debug!("Instance wide: {}", instance);
+
+    let since = m.value_of("since").map(|v| parse_since(v).expect("validated by clap"));
+    debug!("Since: {:?}", since);
+    let (gitlab_url, mirror_group) = match provider {
+        // The stdin provider neither needs an url nor a group
+        Providers::Stdin => (String::new(), String::new()),
+        Providers::Manifest => (value_t_or_exit!(m.value_of("url"), String), String::new()),
+        Providers::GitLab if instance => (value_t_or_exit!(m.value_of("url"), String), String::new()),
+        _ => (
+            value_t_or_exit!(m.value_of("url"), String),
+            value_t_or_exit!(m.value_of("group"), String),
+        ),
+    };
+    let gitlab_url = expand_or_exit("url", &gitlab_url);
     debug!("Using gitlab url: {}", gitlab_url);
-    let mirror_group = value_t_or_exit!(m.value_of("group"), String);
+    let mirror_group = expand_or_exit("group", &mirror_group);
     debug!("Using group: {}", mirror_group);
     let use_http = m.is_present("http");
     debug!("Using http enabled: {}", use_http);
     let dry_run = m.is_present("dry-run");
-    debug!("Dry run: {}", dry_run);
+    let dry_run_refs = m.value_of("dry-run") == Some("refs");
+    debug!("Dry run: {} (refs: {})", dry_run, dry_run_refs);
+    let smoke = m.is_present("smoke");
+    debug!("Smoke test: {}", smoke);
     let worker_count = value_t_or_exit!(m.value_of("worker-count"), usize);
     debug!("Worker count: {}", worker_count);
     let metrics_file = value_t!(m.value_of("metrics-file"), String).ok();
     debug!("Metrics file: {:?}", metrics_file);
+    let summary_file = value_t!(m.value_of("summary-file"), String).ok();
+    debug!("Summary file: {:?}", summary_file);
+    let run_log = m.value_of("run-log").map(|f| {
+        RunLog {
+            path: f.into(),
+            max_bytes: value_t_or_exit!(m.value_of("run-log-max-bytes"), u64),
+        }
+    });
+    debug!("Run log: {:?}", run_log);
+    let report_format = value_t_or_exit!(m.value_of("report-format"), ReportFormats);
+    debug!("Report format: {:?}", report_format);
+    let report_file = value_t!(m.value_of("report-file"), String).ok();
+    debug!("Report file: {:?}", report_file);
+    let mut fetch_workers = value_t_or_exit!(m.value_of("fetch-workers"), usize);
+    for v in m.values_of("concurrency-per-provider").into_iter().flatten() {
+        // Already validated by clap
+        if let Ok((p, workers)) = parse_provider_concurrency(v) {
+            if p == provider {
+                fetch_workers = workers;
+            }
+        }
+    }
+    let fetch_mode = if fetch_workers > 1 {
+        ProviderFetchMode::Parallel { workers: fetch_workers }
+    } else {
+        ProviderFetchMode::Sequential
+    };
+    debug!("Fetch mode: {:?}", fetch_mode);
+    let sort = match value_t!(m.value_of("sort"), Sorting).ok() {
+        Some(Sorting::Origin) => Some(SortKey::Origin),
+        Some(Sorting::Destination) => Some(SortKey::Destination),
+        Some(Sorting::Name) => Some(SortKey::Name),
+        None if m.is_present("sort-by-destination") => Some(SortKey::Destination),
+        None => None,
+    };
+    debug!("Sort: {:?}", sort);
+    let timeout = match value_t_or_exit!(m.value_of("timeout-per-mirror"), u64) {
+        0 => None,
+        t => Some(Duration::from_secs(t)),
+    };
+    debug!("Timeout per mirror: {:?}", timeout);
+    let on_conflict = match value_t_or_exit!(m.value_of("on-conflict"), Conflicts) {
+        Conflicts::Fail => OnConflict::Fail,
+        Conflicts::ForceOverwrite => OnConflict::ForceOverwrite,
+        Conflicts::Skip => OnConflict::Skip,
+    };
+    debug!("On conflict: {:?}", on_conflict);
+    let token_command = value_t!(m.value_of("token-command"), String)
+        .ok()
+        .map(|c| expand_or_exit("token-command", &c));
+    debug!("Token command: {:?}", token_command);
+    let token_resolver = |var: &str| -> Box<dyn TokenResolver> {
+        match token_command {
+            Some(ref c) => Box::new(CommandResolver::new(c)),
+            None => Box::new(EnvResolver::new(var)),
+        }
+    };
+    let all_refs = m.is_present("all-refs");
+    debug!("All refs: {}", all_refs);
+    let isolate_credentials = m.is_present("isolate-credentials");
+    debug!("Isolate credentials: {}", isolate_credentials);
+    let unshallow = m.is_present("unshallow");
+    debug!("Unshallow: {}", unshallow);
+    let keep_locks = m.is_present("no-lock-cleanup");
+    debug!("Keep locks: {}", keep_locks);
+    let limits = Limits {
+        max_refs: m.value_of("max-refs").map(|_| value_t_or_exit!(m.value_of("max-refs"), usize)),
+        max_pack_bytes: m.value_of("max-pack-bytes").map(|_| {
+            value_t_or_exit!(m.value_of("max-pack-bytes"), u64)
+        }),
+    };
+    debug!("Limits: {:?}", limits);
+    let allowed_destination_hosts = m.values_of("allowed-destination-host").map(|v| {
+        v.map(|h| h.to_owned()).collect::<Vec<String>>()
+    });
+    debug!("Allowed destination hosts: {:?}", allowed_destination_hosts);
+    let host_failure_budget = m.value_of("host-failure-budget").map(|_| {
+        value_t_or_exit!(m.value_of("host-failure-budget"), usize)
+    });
+    debug!("Host failure budget: {:?}", host_failure_budget);
+    let adaptive_concurrency = m.is_present("adaptive-concurrency");
+    debug!("Adaptive concurrency: {}", adaptive_concurrency);
 
-    let provider = value_t_or_exit!(m.value_of("provider"), Providers);
+    let gc_threshold = if m.is_present("no-gc") {
+        None
+    } else {
+        Some(value_t_or_exit!(m.value_of("gc-threshold"), u64))
+    };
+    debug!("Gc threshold: {:?}", gc_threshold);
+
+    let submodule_depth = value_t_or_exit!(m.value_of("submodule-depth"), usize);
+    debug!("Submodule depth: {}", submodule_depth);
 
-    let res = match provider {
+    let changed_only = m.is_present("changed-only");
+    debug!("Changed only: {}", changed_only);
+
+    let labels = m.values_of("label").map(|v| v.map(|l| l.to_owned()).collect::<Vec<_>>());
+    debug!("Labels: {:?}", labels);
+
+    let failure_cache = m.value_of("failure-cache").map(|f| {
+        FailureCache::load(
+            f.into(),
+            Duration::from_secs(value_t_or_exit!(m.value_of("failure-cooldown"), u64)),
+        )
+    });
+    debug!("Failure cache: {:?}", failure_cache);
+    let retry_failed = m.is_present("retry-failed");
+    debug!("Retry failed: {}", retry_failed);
+    let warm_up = !m.is_present("no-warm-up");
+    debug!("Warm-up check: {}", warm_up);
+
+    let verify = m.is_present("verify");
+    debug!("Verify: {}", verify);
+
+    let freshness_probe = m.is_present("freshness-probe");
+    debug!("Freshness probe: {}", freshness_probe);
+    let rewrites = Rewrites {
+        origin: rewrites_or_exit(&m, "rewrite-origin"),
+        destination: rewrites_or_exit(&m, "rewrite-destination"),
+    };
+    debug!("Rewrites: {:?}", rewrites);
+    let mut origin_credentials = HashMap::new();
+    for v in m.values_of("origin-credential").into_iter().flatten() {
+        // Already validated by clap
+        if let Ok((host, username, var)) = parse_origin_credential(v) {
+            // Error messages must not contain the value, it is the password
+            let password = env::var(&var).unwrap_or_else(|_| {
+                error!("Invalid value for --origin-credential: environment variable {} is not set", var);
+                exit(2);
+            });
+            origin_credentials.insert(host, Credentials { username, password: Some(password) });
+        }
+    }
+    debug!("Origin credentials: {:?}", origin_credentials);
+
+    let opts = MirrorOptions {
+        mirror_dir,
+        naming_scheme,
+        dry_run,
+        dry_run_refs,
+        smoke,
+        worker_count,
+        metrics_file,
+        sort,
+        timeout,
+        on_conflict,
+        isolate_credentials,
+        unshallow,
+        limits,
+        keep_locks,
+        allowed_destination_hosts,
+        host_failure_budget,
+        submodule_depth,
+        changed_only,
+        labels,
+        rewrites,
+        failure_cache,
+        retry_failed,
+        warm_up,
+        verify,
+        gc_threshold,
+        freshness_probe,
+        adaptive_concurrency,
+        origin_credentials,
+    };
+
+    let p: Box<dyn Provider> = match provider {
         Providers::GitLab => {
             let p = GitLab {
                 url: gitlab_url.to_owned(),
-                group: mirror_group.to_owned(),
+                base_path: m.value_of("base-path").map(|p| p.to_owned()),
+                max_redirects: value_t_or_exit!(m.value_of("max-redirects"), usize),
+                keyset_pagination: m.is_present("keyset-pagination"),
+                exclude_project_ids: m.values_of("exclude-project-id").map(|v| {
+                    v.map(|id| id.parse().expect("validated by clap")).collect()
+                }),
+                exclude_paths: m.values_of("exclude-path").map(|v| v.map(|p| p.to_owned()).collect()),
+                flatten: m.value_of("flatten-into").map(|prefix| Flatten {
+                    prefix: prefix.to_owned(),
+                    separator: m.value_of("flatten-separator").unwrap().to_owned(),
+                    disambiguate: m.is_present("flatten-disambiguate"),
+                }),
+                scope: if instance {
+                    Scope::Instance
+                } else {
+                    Scope::Group(mirror_group.to_owned())
+                },
                 use_http: use_http,
-                private_token: gitlab_private_token,
+                token: Arc::from(token_resolver("GITLAB_PRIVATE_TOKEN")),
                 recursive: true,
+                fetch_mode,
+                all_refs,
+                since,
+                api_cache: m.value_of("api-cache").map(|f| Arc::new(ApiCache::load(f.into()))),
             };
-            do_mirror(&p, worker_count, &mirror_dir, dry_run, metrics_file)
+            Box::new(p)
         }
         Providers::GitHub => {
             let p = GitHub {
                 url: gitlab_url.to_owned(),
                 org: mirror_group.to_owned(),
                 use_http: use_http,
-                private_token: gitlab_private_token,
+                token: token_resolver("GITHUB_PRIVATE_TOKEN"),
                 useragent: format!("{}/{}", crate_name!(), crate_version!()),
             };
-            do_mirror(&p, worker_count, &mirror_dir, dry_run, metrics_file)
+            Box::new(p)
+        }
+        Providers::Stdin => {
+            Box::new(StdinProvider)
+        }
+        Providers::Manifest => {
+            let path = value_t_or_exit!(m.value_of("manifest-path"), String);
+            let revision = m.value_of("manifest-revision").map(|r| r.to_owned());
+            debug!("Manifest: {} at {:?}", path, revision);
+            let cache_dir = Path::new(&opts.mirror_dir).join(".manifest").join(
+                NamingScheme::Slug.local_dir(&gitlab_url),
+            );
+            Box::new(ManifestProvider {
+                repo: gitlab_url.to_owned(),
+                path,
+                revision,
+                cache_dir,
+            })
         }
     };
 
+    warn_unsupported(&m, &*p);
+
+    if let Some(i) = m.subcommand_matches("inventory") {
+        let format = value_t_or_exit!(i.value_of("format"), InventoryFormats);
+        let hash = config_hash(
+            &INVENTORY_ARGS
+                .iter()
+                .map(|&a| match m.values_of(a) {
+                    Some(v) => (a, v.map(|v| v.to_owned()).collect()),
+                    // Flags don't have values
+                    None if m.is_present(a) => (a, vec!["true".to_owned()]),
+                    None => (a, Vec::new()),
+                })
+                .collect::<Vec<_>>(),
+        );
+        let res = Inventory::new(&*p, &opts, hash).and_then(|inventory| {
+            let mut out: Box<dyn io::Write> = match i.value_of("output") {
+                Some(f) => Box::new(File::create(f).map_err(|e| format!("Unable to create {} ({})", f, e))?),
+                None => Box::new(io::stdout()),
+            };
+            match format {
+                InventoryFormats::Json => inventory.write_json(&mut *out),
+                InventoryFormats::Csv => inventory.write_csv(&mut *out),
+            }.map_err(|e| format!("Unable to write inventory ({})", e))
+        });
+        if let Err(e) = res {
+            error!("Error occured: {}", e);
+            exit(2);
+        }
+        return;
+    }
+
+    if m.is_present("explain") {
+        match explain(&*p, &opts) {
+            Ok(explanations) => {
+                for explanation in explanations {
+                    println!("{}", explanation);
+                }
+            }
+            Err(e) => {
+                error!("Error occured: {}", e);
+                exit(2);
+            }
+        }
+        return;
+    }
+
+    if m.is_present("count") {
+        match p.count() {
+            Ok(count) => {
+                println!("{}", count.mirrors);
+                println!(
+                    "{}: {} projects, {} mirrors, {} skipped, {} invalid",
+                    p.get_label(),
+                    count.projects,
+                    count.mirrors,
+                    count.skipped,
+                    count.invalid
+                );
+            }
+            Err(e) => {
+                error!("Error occured: {}", e);
+                exit(2);
+            }
+        }
+        return;
+    }
+
+    let start = Utc::now();
+    let started = Instant::now();
+    let res = do_mirror(&*p, &opts);
+
+    if let Some(run_log) = run_log {
+        let record = RunRecord::new(start, started.elapsed(), p.get_label(), &res);
+        if let Err(e) = run_log.append(&record) {
+            error!("Unable to append to the run log ({})", e);
+            exit(2);
+        }
+    }
+
     match res {
-        Ok(_) => {
+        Ok(summary) => {
+            let formatter: Box<dyn ReportFormatter> = match report_format {
+                ReportFormats::Text => Box::new(TextFormatter),
+                ReportFormats::Json => Box::new(JsonFormatter),
+                ReportFormats::JUnit => Box::new(JUnitFormatter),
+            };
+            let written = match report_file {
+                Some(ref f) => File::create(f)
+                    .and_then(|mut file| formatter.write(&summary, &mut file))
+                    .map_err(|e| format!("Unable to write report file: {:?} ({})", f, e)),
+                None => formatter
+                    .write(&summary, &mut io::stdout())
+                    .map_err(|e| format!("Unable to write report ({})", e)),
+            };
+            if let Err(e) = written {
+                error!("{}", e);
+                exit(2);
+            }
+            if let Some(f) = summary_file {
+                if let Err(e) = write_summary(&f, &summary) {
+                    error!("Unable to write summary file: {:?} ({})", f, e);
+                    exit(2);
+                }
+            }
             info!("All done");
+            if summary.failed > 0 || summary.blocked > 0 {
+                exit(1);
+            }
         }
         Err(e) => {
             error!("Error occured: {}", e);
@@ -160,3 +1022,116 @@ fn main() {
         }
     };
 }
+
+/// Arguments that decide which mirrors a provider returns, they make up the config hash of an inventory
+const INVENTORY_ARGS: &[&str] = &[
+    "provider",
+    "url",
+    "base-path",
+    "flatten-into",
+    "flatten-separator",
+    "flatten-disambiguate",
+    "exclude-project-id",
+    "exclude-path",
+    "group",
+    "instance",
+    "http",
+    "all-refs",
+    "since",
+    "label",
+    "rewrite-origin",
+    "rewrite-destination",
+    "manifest-path",
+    "manifest-revision",
+];
+
+/// Expand the environment variables in the value of an argument or exit
+fn expand_or_exit(arg: &str, value: &str) -> String {
+    expand_env(value).unwrap_or_else(|e| {
+        error!("Invalid value for --{}: {}", arg, e);
+        exit(2);
+    })
+}
+
+/// Warn about arguments that are ignored because the provider doesn't support what they depend on
+fn warn_unsupported(m: &ArgMatches, provider: &dyn Provider) {
+    let capabilities = provider.capabilities();
+    for &(arg, supported, feature) in &[
+        ("since", capabilities.supports_activity, "selecting projects by activity"),
+        ("api-cache", capabilities.supports_api_cache, "caching API listings"),
+    ]
+    {
+        if m.is_present(arg) && !supported {
+            warn!(
+                "Ignoring --{}, {} doesn't support {}",
+                arg,
+                provider.get_label(),
+                feature
+            );
+        }
+    }
+}
+
+/// Parse the `<regex> <replacement>` pairs of a rewrite argument or exit
+fn rewrites_or_exit(m: &ArgMatches, arg: &str) -> Vec<Rewrite> {
+    let values: Vec<&str> = m.values_of(arg).map(|v| v.collect()).unwrap_or_default();
+    values
+        .chunks(2)
+        .map(|rule| {
+            Rewrite::new(rule[0], rule[1]).unwrap_or_else(|e| {
+                error!("Invalid value for --{}: {}", arg, e);
+                exit(2);
+            })
+        })
+        .collect()
+}
+
+/// Parse the time given to --since
+fn parse_since(v: &str) -> Result<DateTime<Utc>, String> {
+    DateTime::parse_from_rfc3339(v)
+        .map(|t| t.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid RFC 3339 time {} ({})", v, e))
+}
+
+/// Parse a `<host>=[<username>:]<env>` origin credential into the lowercase host, username and variable
+fn parse_origin_credential(v: &str) -> Result<(String, Option<String>, String), String> {
+    let mut parts = v.splitn(2, '=');
+    let host = parts.next().unwrap_or("");
+    let rest = parts.next().ok_or_else(|| {
+        format!("Expected `<host>=[<username>:]<env>`, got: {}", v)
+    })?;
+    let (username, var) = match rest.rfind(':') {
+        Some(i) => (Some(rest[..i].to_owned()), &rest[i + 1..]),
+        None => (None, rest),
+    };
+    if host.is_empty() || var.is_empty() {
+        return Err(format!("Expected `<host>=[<username>:]<env>`, got: {}", v));
+    }
+    Ok((host.to_lowercase(), username, var.to_owned()))
+}
+
+/// Parse a `<provider>=<workers>` concurrency override
+fn parse_provider_concurrency(v: &str) -> Result<(Providers, usize), String> {
+    let mut parts = v.splitn(2, '=');
+    let provider = parts.next().unwrap_or("");
+    let workers = parts.next().ok_or_else(|| {
+        format!("Expected `<provider>=<workers>`, got: {}", v)
+    })?;
+    let provider = provider.parse::<Providers>().map_err(|_| {
+        format!(
+            "Unknown provider {}, expected one of: {}",
+            provider,
+            Providers::variants().join(", ")
+        )
+    })?;
+    let workers = match workers.parse::<usize>() {
+        Ok(w) if w > 0 => w,
+        _ => return Err(format!("Invalid number of workers: {}", workers)),
+    };
+    Ok((provider, workers))
+}
+
+fn write_summary(f: &str, summary: &RunSummary) -> Result<(), String> {
+    let mut file = File::create(f).map_err(|e| format!("{}", e))?;
+    JsonFormatter.write(summary, &mut file).map_err(|e| format!("{}", e))
+}
@@ -0,0 +1,83 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+// Used to store the cache
+extern crate serde_json;
+
+/// A cached page of an API listing
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedPage {
+    /// The `ETag` the response was sent with
+    pub etag: String,
+    pub body: String,
+    pub next_page: Option<u32>,
+    pub total_pages: Option<u32>,
+    /// The url of the next page from the `Link` header, used by keyset pagination
+    #[serde(default)]
+    pub next_link: Option<String>,
+}
+
+/// Pages of API listings with their `ETag`, stored in a JSON file between runs
+///
+/// Requests for cached pages are sent with `If-None-Match`, on `304 Not Modified` the cached page is used.
+/// Only the pages used during a run are written back, so pages that no longer exist don't accumulate.
+#[derive(Debug)]
+pub struct ApiCache {
+    path: PathBuf,
+    /// Pages loaded from the file
+    stored: HashMap<String, CachedPage>,
+    /// Pages used during this run
+    used: Mutex<HashMap<String, CachedPage>>,
+}
+
+impl ApiCache {
+    /// Load the cache from path, a missing or unreadable file results in an empty cache
+    pub fn load(path: PathBuf) -> ApiCache {
+        let stored = match File::open(&path) {
+            Ok(f) => {
+                serde_json::from_reader(f).unwrap_or_else(|e| {
+                    warn!("Ignoring invalid API cache {:?} ({})", path, e);
+                    HashMap::new()
+                })
+            }
+            Err(e) => {
+                debug!("No API cache at {:?} ({})", path, e);
+                HashMap::new()
+            }
+        };
+        ApiCache {
+            path,
+            stored,
+            used: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The cached page for url, if any
+    pub fn get(&self, url: &str) -> Option<&CachedPage> {
+        self.stored.get(url)
+    }
+
+    /// Remember page as the current content of url
+    pub fn insert(&self, url: &str, page: CachedPage) {
+        self.used.lock().unwrap().insert(url.to_owned(), page);
+    }
+
+    /// Write the pages used during this run to the cache file
+    pub fn save(&self) -> Result<(), String> {
+        let used = self.used.lock().unwrap();
+        let file = File::create(&self.path).map_err(|e| {
+            format!("Unable to write API cache {:?} ({})", self.path, e)
+        })?;
+        serde_json::to_writer(file, &*used).map_err(|e| {
+            format!("Unable to write API cache {:?} ({})", self.path, e)
+        })
+    }
+}
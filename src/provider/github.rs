@@ -13,7 +13,7 @@ extern crate hyper_native_tls;
 #[cfg(not(feature = "native-tls"))]
 extern crate hyper_rustls;
 use hyper::client::Client;
-use hyper::header::{Headers, Accept, UserAgent, qitem};
+use hyper::header::{Headers, Accept, Authorization, UserAgent, qitem};
 use hyper::status::StatusCode;
 use hyper::net::HttpsConnector;
 
@@ -22,24 +22,17 @@ extern crate serde;
 extern crate serde_json;
 extern crate serde_yaml;
 
-use provider::{Mirror, MirrorResult, MirrorError, Provider};
+use provider::{read_error_body, Desc, Mirror, MirrorResult, MirrorError, Provider, ProviderCapabilities, TokenResolver};
 
 pub struct GitHub {
     pub url: String,
     pub org: String,
     pub use_http: bool,
-    pub private_token: Option<String>,
+    /// Provides the access token for every API request
+    pub token: Box<dyn TokenResolver>,
     pub useragent: String,
 }
 
-/// A structured description
-#[derive(Deserialize, Debug)]
-struct Desc {
-    origin: String,
-    #[serde(default)]
-    skip: bool,
-}
-
 /// A project from the GitLab API
 #[derive(Deserialize, Debug)]
 struct Project {
@@ -49,10 +42,23 @@ struct Project {
     clone_url: String,
 }
 
+impl GitHub {
+    /// The url of the API without trailing slashes
+    fn base_url(&self) -> &str {
+        self.url.trim_end_matches('/')
+    }
+}
 
 impl Provider for GitHub {
     fn get_label(&self) -> String {
-        format!("{}/orgs/{}", self.url, self.org)
+        format!("{}/orgs/{}", self.base_url(), self.org)
+    }
+
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            supports_descriptions: true,
+            ..Default::default()
+        }
     }
 
     fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
@@ -76,7 +82,12 @@ impl Provider for GitHub {
             qitem("application/vnd.github.v3+json".parse().unwrap()),
         ]));
 
-        let url = format!("{}/orgs/{}/repos", self.url, self.org);
+        match self.token.resolve()? {
+            Some(token) => headers.set(Authorization(format!("token {}", token))),
+            None => debug!("No access token provided by {}", self.token.describe()),
+        }
+
+        let url = format!("{}/orgs/{}/repos", self.base_url(), self.org);
         trace!("URL: {}", url);
 
         let res = client.get(&url).headers(headers).send().or_else(|e| {
@@ -87,16 +98,18 @@ impl Provider for GitHub {
             if res.status == StatusCode::Unauthorized {
                 return Err(format!(
                     "API call received unautorized ({}) for: {}. \
-                                   Please make sure the `GITHUB_PRIVATE_TOKEN` environment \
-                                   variable is set.",
+                                   Please make sure {} provides a valid token.",
                     res.status,
-                    url
+                    url,
+                    self.token.describe()
                 ));
             } else {
+                let status = res.status;
                 return Err(format!(
-                    "API call received invalid status ({}) for : {}",
-                    res.status,
-                    url
+                    "API call received invalid status ({}) for : {} ({})",
+                    status,
+                    url,
+                    read_error_body(res)
                 ));
             }
         }
@@ -108,7 +121,9 @@ impl Provider for GitHub {
         let mut mirrors: Vec<MirrorResult> = Vec::new();
 
         for p in projects {
-            match serde_yaml::from_str::<Desc>(&p.description.unwrap_or_default()) {
+            let desc = serde_yaml::from_str::<Desc>(&p.description.unwrap_or_default())
+                .and_then(Desc::without_protected_only);
+            match desc {
                 Ok(desc) => {
                     if desc.skip {
                         mirrors.push(Err(MirrorError::Skip(p.url)));
@@ -116,11 +131,7 @@ impl Provider for GitHub {
                     }
                     trace!("{0} -> {1}", desc.origin, p.ssh_url);
                     let destination = if use_http { p.clone_url } else { p.ssh_url };
-                    let m = Mirror {
-                        origin: desc.origin,
-                        destination: destination,
-                    };
-                    mirrors.push(Ok(m));
+                    mirrors.push(Ok(Mirror::from_desc(desc, destination, true)));
                 }
                 Err(e) => {
                     mirrors.push(Err(MirrorError::Description(p.url, e)));
@@ -130,3 +141,33 @@ impl Provider for GitHub {
         return Ok(mirrors);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use provider::EnvResolver;
+
+    fn github(url: &str) -> GitHub {
+        GitHub {
+            url: url.to_owned(),
+            org: "mirror".to_owned(),
+            use_http: false,
+            token: Box::new(EnvResolver::new("GIT_MIRROR_TEST_TOKEN_NOT_SET")),
+            useragent: "git-mirror".to_owned(),
+        }
+    }
+
+    #[test]
+    fn base_url_has_no_trailing_slash() {
+        assert_eq!(github("https://api.github.com").base_url(), "https://api.github.com");
+        assert_eq!(github("https://api.github.com/").base_url(), "https://api.github.com");
+        assert_eq!(github("https://api.github.com/").get_label(), "https://api.github.com/orgs/mirror");
+    }
+
+    #[test]
+    fn base_url_keeps_the_subpath() {
+        // GitHub Enterprise serves the API below /api/v3
+        assert_eq!(github("https://ghe.example.com/api/v3").base_url(), "https://ghe.example.com/api/v3");
+        assert_eq!(github("https://ghe.example.com/api/v3//").base_url(), "https://ghe.example.com/api/v3");
+    }
+}
@@ -4,8 +4,19 @@
  * SPDX-License-Identifier:     MIT
  */
 
-// Get Max of u32
-use std::u32;
+// Used to read response bodies
+use std::io::Read;
+
+// Used for the renamed branches of a mirror
+use std::collections::BTreeMap;
+
+// Used to share the client between fetch workers
+use std::sync::Arc;
+use std::sync::mpsc::channel;
+use threadpool::ThreadPool;
+
+// Used to filter projects by their last activity
+use chrono::{DateTime, Utc};
 
 // Used for error and debug logging
 extern crate log;
@@ -15,10 +26,11 @@ extern crate log;
 extern crate hyper_native_tls;
 #[cfg(not(feature = "native-tls"))]
 extern crate hyper_rustls;
-use hyper::client::Client;
-use hyper::header::Headers;
+use hyper::client::{Client, RedirectPolicy, Response};
+use hyper::header::{EntityTag, ETag, Headers, IfNoneMatch, Location};
 use hyper::status::StatusCode;
 use hyper::net::HttpsConnector;
+use hyper::Url;
 
 // Custom header used to access the gitlab API
 // See: https://docs.gitlab.com/ce/api/#authentication
@@ -27,39 +39,110 @@ header! { (PrivateToken, "PRIVATE-TOKEN") => [String] }
 // Custom header to check for pagination
 header! { (XNextPage, "X-Next-Page") => [u32] }
 
+// Custom header containing the total number of pages
+header! { (XTotalPages, "X-Total-Pages") => [u32] }
+
 // Used to serialize JSON and YAML responses from the API
 extern crate serde;
+use self::serde::de::Error;
 extern crate serde_json;
 extern crate serde_yaml;
 
-use provider::{Mirror, MirrorResult, MirrorError, Provider};
+use provider::{guess_description, read_error_body, ApiCache, CachedPage, Desc, ExplainedMirrors, FilteredProject, Mirror,
+               MirrorResult, MirrorError, Provider, ProjectCount, ProviderCapabilities, ProviderFetchMode, ProviderMeta, TokenResolver};
+use layout::Flatten;
+
+/// The set of projects to look at for mirrors
+#[derive(Debug, Clone)]
+pub enum Scope {
+    /// All projects of a group (and its subgroups if recursive)
+    Group(String),
+    /// All projects of the instance, this requires an admin token
+    Instance,
+}
 
 #[derive(Debug)]
 pub struct GitLab {
     pub url: String,
-    pub group: String,
+    /// Path of an instance served below the root of its host, e.g. `/gitlab`, appended to url
+    pub base_path: Option<String>,
+    pub scope: Scope,
     pub use_http: bool,
-    pub private_token: Option<String>,
+    /// Provides the private token for every API request
+    pub token: Arc<dyn TokenResolver>,
     pub recursive: bool,
-}
-
-/// A structured description
-#[derive(Deserialize, Debug)]
-struct Desc {
-    origin: String,
-    #[serde(default)]
-    skip: bool,
+    pub fetch_mode: ProviderFetchMode,
+    /// Push all refs by default, including `refs/merge-requests/*` and `refs/pipelines/*`
+    pub all_refs: bool,
+    /// Only list projects with activity since this time
+    pub since: Option<DateTime<Utc>>,
+    /// Pages of listings with their ETag, saved after every complete listing
+    pub api_cache: Option<Arc<ApiCache>>,
+    /// Maximum number of redirects followed for a single API request, see `get`
+    pub max_redirects: usize,
+    /// Derive the destination from the flattened project path instead of using the project itself
+    pub flatten: Option<Flatten>,
+    /// List projects with keyset instead of offset pagination, see `get_keyset`
+    pub keyset_pagination: bool,
+    /// Projects that are never mirrored, whatever their description says
+    pub exclude_project_ids: Option<Vec<u64>>,
+    /// Paths with namespace of projects that are never mirrored, e.g. `group/project`, compared case insensitively
+    pub exclude_paths: Option<Vec<String>>,
 }
 
 /// A project from the GitLab API
 #[derive(Deserialize, Debug, Clone)]
 struct Project {
-    description: String,
+    #[serde(default)]
+    id: u64,
+    name: String,
+    #[serde(default)]
+    path_with_namespace: String,
+    description: Option<String>,
+    #[serde(default)]
+    visibility: Option<String>,
+    #[serde(default)]
+    last_activity_at: Option<String>,
     web_url: String,
     ssh_url_to_repo: String,
     http_url_to_repo: String,
 }
 
+/// The current user from the GitLab API
+#[derive(Deserialize, Debug)]
+struct User {
+    username: String,
+    // Only contained in the response for admins
+    #[serde(default)]
+    is_admin: bool,
+}
+
+/// The token of a request from the GitLab API, personal access and impersonation tokens are both listed
+#[derive(Deserialize, Debug)]
+struct Token {
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    revoked: bool,
+    /// Expired tokens aren't active
+    #[serde(default)]
+    active: Option<bool>,
+}
+
+// Scopes of which the token needs at least one to list projects
+const API_SCOPES: &[&str] = &["api", "read_api"];
+
+// Scopes of which the token needs at least one to access repositories over HTTP(S)
+const REPOSITORY_SCOPES: &[&str] = &["read_repository", "write_repository"];
+
+/// A protected branch of a project from the GitLab API, the name may contain `*` wildcards
+#[derive(Deserialize, Debug, Clone)]
+struct ProtectedBranch {
+    name: String,
+}
+
 /// A (sub)group from the GitLab API
 #[derive(Deserialize, Debug, Clone)]
 struct Group {
@@ -69,88 +152,691 @@ struct Group {
 // Number of items per page to request
 const PER_PAGE: u8 = 100;
 
+/// A single page of a paginated API call
+struct Page<T> {
+    results: Vec<T>,
+    next_page: Option<u32>,
+    /// Total number of pages, if known
+    total_pages: Option<u32>,
+    /// The url of the next page from the `Link` header
+    next_link: Option<String>,
+}
+
+// Maximum number of bytes of an unparseable response body logged at debug level
+const MAX_DEBUG_BODY_LEN: usize = 1024;
+
+/// The headers of a request with the current private token
+fn auth_headers(headers: &Headers, token: &dyn TokenResolver) -> Result<Headers, String> {
+    let mut headers = headers.clone();
+    match token.resolve()? {
+        Some(token) => headers.set(PrivateToken(token)),
+        None => debug!("No private token provided by {}", token.describe()),
+    }
+    Ok(headers)
+}
+
+/// Send a GET request to url, following up to max_redirects redirects
+///
+/// hyper would send the private token to whatever host a redirect points to, so redirects are followed
+/// here instead. The private token is only sent again if the redirect stays on the same origin (scheme,
+/// host and port) and doesn't switch from HTTPS to HTTP.
+fn get(client: &Client, url: &str, headers: Headers, max_redirects: usize) -> Result<Response, String> {
+    let first = Url::parse(url).map_err(|e| format!("Invalid url: {} ({})", url, e))?;
+    let mut url = first.clone();
+    let mut headers = headers;
+    let mut redirects = 0;
+    loop {
+        let res = client.get(url.clone()).headers(headers.clone()).send().map_err(|e| {
+            format!("Unable to connect to: {} ({})", url, e)
+        })?;
+        let redirected = matches!(
+            res.status,
+            StatusCode::MovedPermanently | StatusCode::Found | StatusCode::SeeOther |
+                StatusCode::TemporaryRedirect | StatusCode::PermanentRedirect
+        );
+        // Without a location the caller reports the unexpected status
+        let location = match res.headers.get::<Location>() {
+            Some(Location(location)) if redirected => location.clone(),
+            _ => return Ok(res),
+        };
+        if redirects == max_redirects {
+            return Err(format!(
+                "API call for: {} was redirected more than {} times, the last time to: {}",
+                first,
+                max_redirects,
+                location
+            ));
+        }
+        redirects += 1;
+        let next = url.join(&location).map_err(|e| {
+            format!("Invalid redirect from: {} to: {} ({})", url, location, e)
+        })?;
+        // Scheme, host and port, another port may be another service of the same host
+        let same_origin = next.origin() == first.origin() &&
+            !(first.scheme() == "https" && next.scheme() != "https");
+        if !same_origin && headers.has::<PrivateToken>() {
+            warn!("Not sending the private token to {}, it is on another origin than {}", next, first);
+            headers.remove::<PrivateToken>();
+        }
+        info!("Following redirect ({}) from {} to {}", res.status, url, next);
+        url = next;
+    }
+}
+
+/// Parse the JSON body of a page
+fn parse_page<T: serde::de::DeserializeOwned>(
+    url: &str,
+    page: u32,
+    body: &[u8],
+) -> Result<Vec<T>, String> {
+    serde_json::from_slice(body).map_err(|e| {
+        // Often an HTML error page of a proxy or a changed API
+        debug!(
+            "Unparseable body of page {} ({} bytes): {}",
+            page,
+            body.len(),
+            String::from_utf8_lossy(&body[..body.len().min(MAX_DEBUG_BODY_LEN)])
+        );
+        format!("Unable to parse page {} of: {} as JSON ({})", page, url, e)
+    })
+}
+
+/// Fetch a single page of a paginated API call
+///
+/// With a cache, a cached page is requested with `If-None-Match` and reused if it wasn't modified.
+fn get_page<T: serde::de::DeserializeOwned>(
+    url: &str,
+    page: u32,
+    client: &Client,
+    headers: &Headers,
+    token: &dyn TokenResolver,
+    cache: Option<&ApiCache>,
+    max_redirects: usize,
+) -> Result<Page<T>, String> {
+    let sep = if url.contains('?') { '&' } else { '?' };
+    let url = format!("{}{}per_page={}&page={}", url, sep, PER_PAGE, page);
+    fetch_page(&url, page, client, headers, token, cache, max_redirects)
+}
+
+/// Fetch the page at url of a paginated API call, page is its number in the listing
+fn fetch_page<T: serde::de::DeserializeOwned>(
+    url: &str,
+    page: u32,
+    client: &Client,
+    headers: &Headers,
+    token: &dyn TokenResolver,
+    cache: Option<&ApiCache>,
+    max_redirects: usize,
+) -> Result<Page<T>, String> {
+    trace!("URL: {}", url);
+
+    let mut headers = auth_headers(headers, token)?;
+    let cached = cache.and_then(|c| c.get(url));
+    if let Some(tag) = cached.and_then(|c| c.etag.parse::<EntityTag>().ok()) {
+        headers.set(IfNoneMatch::Items(vec![tag]));
+    }
+    let mut res = get(client, url, headers, max_redirects)?;
+
+    debug!("HTTP Status Received: {}", res.status);
+
+    if res.status == StatusCode::NotModified {
+        if let (Some(cache), Some(cached)) = (cache, cached) {
+            trace!("Page {} not modified, using the cached page", page);
+            cache.insert(url, cached.clone());
+            return Ok(Page {
+                results: parse_page(url, page, cached.body.as_bytes())?,
+                next_page: cached.next_page,
+                total_pages: cached.total_pages,
+                next_link: cached.next_link.clone(),
+            });
+        }
+    }
+
+    if res.status != StatusCode::Ok {
+        if res.status == StatusCode::Unauthorized {
+            return Err(format!(
+                "API call received unautorized ({}) for: {}. \
+                           Please make sure {} provides a valid token.",
+                res.status,
+                url,
+                token.describe()
+            ));
+        } else {
+            let status = res.status;
+            return Err(format!(
+                "API call received invalid status ({}) for : {} ({})",
+                status,
+                url,
+                read_error_body(res)
+            ));
+        }
+    }
+
+    let next_page = match res.headers.get::<XNextPage>() {
+        None => {
+            trace!("No more pages");
+            None
+        }
+        Some(n) => {
+            trace!("Next page: {}", n);
+            Some(n.0)
+        }
+    };
+
+    // GitLab omits the total for very large collections
+    let total_pages = res.headers.get::<XTotalPages>().map(|n| n.0);
+
+    let next_link = res.headers.get_raw("Link").and_then(|values| {
+        values.iter().filter_map(|v| link_next(&String::from_utf8_lossy(v))).next()
+    });
+
+    // Servers without conditional requests don't send an ETag, those pages aren't cached
+    let etag = res.headers.get::<ETag>().map(|t| t.0.to_string());
+
+    let mut body = Vec::new();
+    res.read_to_end(&mut body).map_err(|e| {
+        format!("Unable to read page {} of: {} ({})", page, url, e)
+    })?;
+    let results_page: Vec<T> = parse_page(url, page, &body)?;
+
+    if let (Some(cache), Some(etag)) = (cache, etag) {
+        cache.insert(
+            url,
+            CachedPage {
+                etag,
+                body: String::from_utf8_lossy(&body).into_owned(),
+                next_page,
+                total_pages,
+                next_link: next_link.clone(),
+            },
+        );
+    }
+
+    Ok(Page {
+        results: results_page,
+        next_page,
+        total_pages,
+        next_link,
+    })
+}
+
+/// The url of the `rel="next"` link in the value of a `Link` header
+///
+/// The value is a list like `<https://host/api/v4/projects?cursor=x>; rel="next", <...>; rel="first"`,
+/// a link can have several relations separated by spaces, e.g. `rel="next last"`.
+fn link_next(value: &str) -> Option<String> {
+    value.split(',').find_map(|link| {
+        let mut parts = link.split(';');
+        let url = parts.next()?.trim();
+        let is_next = parts.any(|p| {
+            let mut param = p.splitn(2, '=');
+            param.next().is_some_and(|name| name.trim().eq_ignore_ascii_case("rel")) &&
+                param.next().is_some_and(|rel| {
+                    rel.trim().trim_matches('"').split_whitespace().any(|r| r.eq_ignore_ascii_case("next"))
+                })
+        });
+        if is_next && url.starts_with('<') && url.ends_with('>') {
+            Some(url[1..url.len() - 1].to_owned())
+        } else {
+            None
+        }
+    })
+}
+
+/// Whether project had activity at or after since, projects without a known last activity are kept
+fn is_active_since(project: &Project, since: DateTime<Utc>) -> bool {
+    match project.last_activity_at.as_ref().map(|t| DateTime::parse_from_rfc3339(t)) {
+        Some(Ok(t)) => t.with_timezone(&Utc) >= since,
+        _ => true,
+    }
+}
+
 impl GitLab {
-    fn get_paged<T: serde::de::DeserializeOwned>(
+    /// The url of the instance including the base path, without trailing slashes
+    ///
+    /// Slashes around the base path are normalized, so `https://host/` with `gitlab/` is `https://host/gitlab`.
+    fn base_url(&self) -> String {
+        let url = self.url.trim_end_matches('/');
+        match self.base_path.as_ref().map(|p| p.trim_matches('/')) {
+            Some(path) if !path.is_empty() => format!("{}/{}", url, path),
+            _ => url.to_owned(),
+        }
+    }
+
+    /// The url of an API endpoint, path is relative to `/api/v4/`
+    fn api_url(&self, path: &str) -> String {
+        format!("{}/api/v4/{}", self.base_url(), path)
+    }
+
+    fn get_paged<T: serde::de::DeserializeOwned + Send + 'static>(
         &self,
         url: &str,
-        client: &Client,
+        client: &Arc<Client>,
         headers: &Headers,
     ) -> Result<Vec<T>, String> {
-        let mut results: Vec<T> = Vec::new();
-
-        for page in 1..u32::MAX {
-            let url = format!("{}?per_page={}&page={}", url, PER_PAGE, page);
-            trace!("URL: {}", url);
+        let cache = self.api_cache.as_deref();
+        let first = get_page::<T>(url, 1, client, headers, &*self.token, cache, self.max_redirects)?;
+        let mut results = first.results;
+        let mut next_page = first.next_page;
 
-            let res = client.get(&url).headers(headers.clone()).send().or_else(
-                |e| {
-                    Err(format!("Unable to connect to: {} ({})", url, e))
-                },
-            )?;
-
-            debug!("HTTP Status Received: {}", res.status);
-
-            if res.status != StatusCode::Ok {
-                if res.status == StatusCode::Unauthorized {
-                    return Err(format!(
-                        "API call received unautorized ({}) for: {}. \
-                                   Please make sure the `GITLAB_PRIVATE_TOKEN` environment \
-                                   variable is set.",
-                        res.status,
-                        url
-                    ));
-                } else {
-                    return Err(format!(
-                        "API call received invalid status ({}) for : {}",
-                        res.status,
-                        url
-                    ));
+        if let ProviderFetchMode::Parallel { workers } = self.fetch_mode {
+            match first.total_pages {
+                Some(total_pages) if total_pages > 1 => {
+                    let pool = ThreadPool::new(workers);
+                    let (tx, rx) = channel();
+                    for page in 2..(total_pages + 1) {
+                        let tx = tx.clone();
+                        let url = url.to_owned();
+                        let client = client.clone();
+                        let headers = headers.clone();
+                        let token = self.token.clone();
+                        let cache = self.api_cache.clone();
+                        let max_redirects = self.max_redirects;
+                        pool.execute(move || {
+                            let res = get_page::<T>(
+                                &url,
+                                page,
+                                &client,
+                                &headers,
+                                &*token,
+                                cache.as_deref(),
+                                max_redirects,
+                            );
+                            // The listing may already have failed on another page
+                            let _ = tx.send(res.map(|p| p.results));
+                        });
+                    }
+                    // Only the workers can send now, the pages end when all of them are done
+                    drop(tx);
+                    // Pages arrive in completion order, not in page order
+                    let mut fetched = 1;
+                    for results_page in rx.iter() {
+                        results.extend(results_page?);
+                        fetched += 1;
+                    }
+                    if fetched < total_pages {
+                        return Err(format!("Unable to fetch all {} pages of: {}", total_pages, url));
+                    }
+                    return Ok(results);
                 }
+                // A single page or an empty listing, nothing to fetch in parallel
+                Some(_) => {}
+                None => debug!("Total number of pages unknown, falling back to sequential fetching"),
             }
+        }
 
+        while let Some(page) = next_page {
+            let page = get_page::<T>(url, page, client, headers, &*self.token, cache, self.max_redirects)?;
+            results.extend(page.results);
+            next_page = page.next_page;
+        }
+        Ok(results)
+    }
 
-            let has_next = match res.headers.get::<XNextPage>() {
-                None => {
-                    trace!("No more pages");
-                    false
+    /// Fetch all pages at url with keyset pagination, ordered by id, following the `rel="next"` links
+    ///
+    /// Unlike offset pagination, projects created or removed during the listing don't shift the pages,
+    /// so no project is missed or listed twice. Without a usable link, e.g. because the endpoint doesn't
+    /// support keyset pagination or the link points to another host, the next page is requested by offset.
+    /// A link to another host without a next page number fails the listing, it would be incomplete.
+    fn get_keyset<T: serde::de::DeserializeOwned>(
+        &self,
+        url: &str,
+        client: &Client,
+        headers: &Headers,
+    ) -> Result<Vec<T>, String> {
+        let cache = self.api_cache.as_deref();
+        let sep = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}order_by=id&sort=asc", url, sep);
+        let first = format!("{}&pagination=keyset&per_page={}", url, PER_PAGE);
+        let mut page = fetch_page::<T>(&first, 1, client, headers, &*self.token, cache, self.max_redirects)?;
+        let mut results = Vec::new();
+        for number in 2.. {
+            let next_page = page.next_page;
+            let next_link = match page.next_link.take() {
+                Some(link) => {
+                    let same_origin = Url::parse(&link).ok().map(|l| l.origin()) ==
+                        Url::parse(&url).ok().map(|u| u.origin());
+                    if same_origin {
+                        Some(link)
+                    } else if next_page.is_some() {
+                        warn!("Not following the link to the next page {}, it is on another host than {}", link, url);
+                        None
+                    } else {
+                        return Err(format!(
+                            "Unable to list all pages of: {}, the link to the next page {} is on another host",
+                            url,
+                            link
+                        ));
+                    }
                 }
-                Some(n) => {
-                    trace!("Next page: {}", n);
-                    true
+                None => None,
+            };
+            results.extend(page.results);
+            page = match (next_link, next_page) {
+                (Some(link), _) => {
+                    fetch_page::<T>(&link, number, client, headers, &*self.token, cache, self.max_redirects)?
+                }
+                (None, Some(next_page)) => {
+                    debug!("No link to page {} of {}, using offset pagination", number, url);
+                    get_page::<T>(&url, next_page, client, headers, &*self.token, cache, self.max_redirects)?
                 }
+                (None, None) => break,
             };
+        }
+        Ok(results)
+    }
 
-            let results_page: Vec<T> = serde_json::from_reader(res).or_else(|e| {
-                Err(format!("Unable to parse response as JSON ({})", e))
-            })?;
-
-            results.extend(results_page);
+    /// Fetch the projects at url, only the pages with projects active since `since` if it is set
+    ///
+    /// With `since` the projects are requested by descending last activity and fetched page by page,
+    /// pagination stops after the first page containing an older project. The older projects of that
+    /// page are returned as well, they are filtered by `list_projects_filtered`. Keyset pagination is only
+    /// used without `since`, since it orders by id.
+    fn get_project_pages(
+        &self,
+        url: &str,
+        client: &Arc<Client>,
+        headers: &Headers,
+    ) -> Result<Vec<Project>, String> {
+        let since = match self.since {
+            Some(since) => since,
+            None if self.keyset_pagination => return self.get_keyset::<Project>(url, client, headers),
+            None => return self.get_paged::<Project>(url, client, headers),
+        };
+        if self.keyset_pagination {
+            debug!("Projects are ordered by last activity, using offset pagination");
+        }
+        let sep = if url.contains('?') { '&' } else { '?' };
+        let url = format!("{}{}order_by=last_activity_at&sort=desc", url, sep);
 
-            if !has_next {
+        let mut projects = Vec::new();
+        let mut next_page = Some(1);
+        while let Some(page) = next_page {
+            let results = get_page::<Project>(
+                &url,
+                page,
+                client,
+                headers,
+                &*self.token,
+                self.api_cache.as_deref(),
+                self.max_redirects,
+            )?;
+            let reached_older = results.results.iter().any(|p| !is_active_since(p, since));
+            projects.extend(results.results);
+            if reached_older {
+                // All projects on the following pages are older
+                debug!("Page {} reached projects inactive since {}, stopping", page, since);
                 break;
             }
+            next_page = results.next_page;
         }
-        Ok(results)
+        Ok(projects)
     }
 
     fn get_projects(
         &self,
         id: &str,
-        client: &Client,
+        client: &Arc<Client>,
         headers: &Headers,
     ) -> Result<Vec<Project>, String> {
-        let url = format!("{}/api/v4/groups/{}/projects", self.url, id);
+        let url = self.api_url(&format!("groups/{}/projects", id));
+
+        self.get_project_pages(&url, client, headers)
+    }
+
+    fn get_instance_projects(
+        &self,
+        client: &Arc<Client>,
+        headers: &Headers,
+    ) -> Result<Vec<Project>, String> {
+        self.check_admin(client, headers)?;
+
+        let url = self.api_url("projects?membership=false");
+
+        self.get_project_pages(&url, client, headers)
+    }
+
+    /// Make sure the token is valid and has the scopes needed for listing, before anything is listed
+    ///
+    /// Instances without `personal_access_tokens/self` (before GitLab 15.5) and tokens that can't read
+    /// their own scopes are checked with `/user` instead, that only shows whether the token is valid.
+    fn check_token(&self, client: &Client, headers: &Headers) -> Result<(), String> {
+        let headers = auth_headers(headers, &*self.token)?;
+        if !headers.has::<PrivateToken>() {
+            return Ok(());
+        }
+
+        let url = self.api_url("personal_access_tokens/self");
+        trace!("URL: {}", url);
+        let res = get(client, &url, headers.clone(), self.max_redirects)?;
+        if res.status == StatusCode::Unauthorized {
+            return Err(self.invalid_token(res.status));
+        }
+        if res.status != StatusCode::Ok {
+            debug!("Unable to read the scopes of the token ({}), checking it with /user", res.status);
+            let url = self.api_url("user");
+            trace!("URL: {}", url);
+            let res = get(client, &url, headers, self.max_redirects)?;
+            return match res.status {
+                StatusCode::Ok => Ok(()),
+                StatusCode::Unauthorized => Err(self.invalid_token(res.status)),
+                StatusCode::Forbidden => Err(format!(
+                    "The token from {} isn't allowed to use the API ({}), it needs the read_api scope",
+                    self.token.describe(),
+                    res.status
+                )),
+                status => {
+                    warn!("Unable to validate the token from {} ({})", self.token.describe(), status);
+                    Ok(())
+                }
+            };
+        }
+
+        let token: Token = serde_json::from_reader(res).map_err(|e| {
+            format!("Unable to parse response as JSON ({})", e)
+        })?;
+        debug!("Token {} has the scopes {:?}", token.name, token.scopes);
+        if token.revoked || token.active == Some(false) {
+            return Err(format!(
+                "The token {} from {} is revoked or expired",
+                token.name,
+                self.token.describe()
+            ));
+        }
+        let has_scope = |scopes: &[&str]| token.scopes.iter().any(|s| scopes.contains(&s.as_str()));
+        if !has_scope(API_SCOPES) {
+            return Err(format!(
+                "The token {} from {} lacks the read_api scope needed to list projects, it has the scopes: {}",
+                token.name,
+                self.token.describe(),
+                token.scopes.join(", ")
+            ));
+        }
+        if self.use_http && !has_scope(REPOSITORY_SCOPES) {
+            warn!(
+                "The token {} lacks the read_repository scope, fetches and pushes over HTTPS fail if they use it",
+                token.name
+            );
+        }
+        Ok(())
+    }
+
+    /// The error for a token the API rejected with status
+    fn invalid_token(&self, status: StatusCode) -> String {
+        format!(
+            "The token from {} is invalid, expired or revoked ({})",
+            self.token.describe(),
+            status
+        )
+    }
 
-        self.get_paged::<Project>(&url, &client, &headers)
+    /// Make sure the token belongs to an admin, otherwise only visible projects are listed
+    fn check_admin(&self, client: &Client, headers: &Headers) -> Result<(), String> {
+        let url = self.api_url("user");
+        trace!("URL: {}", url);
+
+        let headers = auth_headers(headers, &*self.token)?;
+        let res = get(client, &url, headers, self.max_redirects)?;
+
+        if res.status != StatusCode::Ok {
+            return Err(format!(
+                "Unable to verify admin access ({}) for: {}. \
+                 Listing all projects of an instance requires an admin token from {}.",
+                res.status,
+                url,
+                self.token.describe()
+            ));
+        }
+
+        let user: User = serde_json::from_reader(res).map_err(|e| {
+            format!("Unable to parse response as JSON ({})", e)
+        })?;
+
+        if !user.is_admin {
+            return Err(format!(
+                "User {} is not an admin. Listing all projects of an instance \
+                 requires an admin token.",
+                user.username
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// A client for the API, redirects are followed by `get`, so the private token isn't sent to other hosts
+    fn client(&self) -> Arc<Client> {
+        #[cfg(feature = "native-tls")]
+        let tls =
+            hyper_native_tls::NativeTlsClient::new().expect("Unable to initialize TLS system");
+        #[cfg(not(feature = "native-tls"))]
+        let tls = hyper_rustls::TlsClient::new();
+
+        let connector = HttpsConnector::new(tls);
+        let mut client = Client::with_connector(connector);
+        client.set_redirect_policy(RedirectPolicy::FollowNone);
+        Arc::new(client)
+    }
+
+    /// All projects in scope that aren't filtered
+    fn list_projects(&self) -> Result<Vec<Project>, String> {
+        Ok(
+            self.list_projects_filtered()?
+                .into_iter()
+                .filter(|(_, reason)| reason.is_none())
+                .map(|(p, _)| p)
+                .collect(),
+        )
+    }
+
+    /// All projects in scope, with the reason if a project is filtered by activity or the exclusions
+    fn list_projects_filtered(&self) -> Result<Vec<(Project, Option<String>)>, String> {
+        let client = self.client();
+
+        // The private token is added to every request by auth_headers
+        let headers = Headers::new();
+
+        self.check_token(&client, &headers)?;
+
+        let mut projects: Vec<Project> = Vec::new();
+
+        match self.scope {
+            Scope::Group(ref group) => {
+                let groups = if self.recursive {
+                    self.get_subgroups(group, &client, &headers)?
+                } else {
+                    vec![group.clone()]
+                };
+
+                for group in groups {
+                    projects.extend(self.get_projects(&group, &client, &headers)?);
+                }
+            }
+            Scope::Instance => {
+                projects.extend(self.get_instance_projects(&client, &headers)?);
+            }
+        }
+
+        if let Some(ref cache) = self.api_cache {
+            if let Err(e) = cache.save() {
+                warn!("{}", e);
+            }
+        }
+
+        Ok(
+            projects
+                .into_iter()
+                .map(|p| {
+                    let reason = self.filter_reason(&p);
+                    (p, reason)
+                })
+                .collect(),
+        )
+    }
+
+    /// Why project is left out, if it is inactive since `since` or excluded by id or path
+    fn filter_reason(&self, project: &Project) -> Option<String> {
+        if let Some(since) = self.since {
+            if !is_active_since(project, since) {
+                return Some(format!("inactive since {}", since.to_rfc3339()));
+            }
+        }
+        let by_id = self.exclude_project_ids.as_ref().is_some_and(|ids| ids.contains(&project.id));
+        let by_path = self.exclude_paths.as_ref().is_some_and(|paths| {
+            paths.iter().any(|path| {
+                path.trim_matches('/').eq_ignore_ascii_case(&project.path_with_namespace)
+            })
+        });
+        if by_id || by_path {
+            trace!("Excluding project {} ({})", project.path_with_namespace, project.id);
+        }
+        if by_id {
+            Some("excluded by id".to_owned())
+        } else if by_path {
+            Some("excluded by path".to_owned())
+        } else {
+            None
+        }
+    }
+
+    /// Refspecs pushing the protected branches of the project with id and all tags, path is used for logging
+    ///
+    /// Branches in branch_map are pushed under their new name, wildcard protections aren't renamed.
+    ///
+    /// The listing is requested with the API cache like the project listings, an unchanged
+    /// listing costs a `304 Not Modified` response.
+    fn protected_refspecs(
+        &self,
+        id: u64,
+        path: &str,
+        branch_map: Option<&BTreeMap<String, String>>,
+        client: &Arc<Client>,
+    ) -> Result<Vec<String>, String> {
+        let url = self.api_url(&format!("projects/{}/protected_branches", id));
+        let branches = self.get_paged::<ProtectedBranch>(&url, client, &Headers::new())?;
+        debug!(
+            "Protected branches of {}: {:?}",
+            path,
+            branches.iter().map(|b| &b.name).collect::<Vec<_>>()
+        );
+        let mut refspecs: Vec<String> = branches
+            .iter()
+            .map(|b| {
+                let to = branch_map.and_then(|m| m.get(&b.name)).unwrap_or(&b.name);
+                format!("+refs/heads/{}:refs/heads/{}", b.name, to)
+            })
+            .collect();
+        refspecs.push("+refs/tags/*:refs/tags/*".to_owned());
+        Ok(refspecs)
     }
 
     fn get_subgroups(
         &self,
         id: &str,
-        client: &Client,
+        client: &Arc<Client>,
         headers: &Headers,
     ) -> Result<Vec<String>, String> {
-        let url = format!("{}/api/v4/groups/{}/subgroups", self.url, id);
+        let url = self.api_url(&format!("groups/{}/subgroups", id));
 
         let groups = self.get_paged::<Group>(&url, &client, &headers)?;
 
@@ -170,69 +856,455 @@ impl GitLab {
 
 impl Provider for GitLab {
     fn get_label(&self) -> String {
-        format!("{}/{}", self.url, self.group)
+        match self.scope {
+            Scope::Group(ref group) => format!("{}/{}", self.base_url(), group),
+            Scope::Instance => self.base_url(),
+        }
     }
 
-    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
-
-        #[cfg(feature = "native-tls")]
-        let tls =
-            hyper_native_tls::NativeTlsClient::new().expect("Unable to initialize TLS system");
-        #[cfg(not(feature = "native-tls"))]
-        let tls = hyper_rustls::TlsClient::new();
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities {
+            supports_descriptions: true,
+            supports_subgroups: self.recursive,
+            // The topics of projects aren't read
+            supports_topics: false,
+            supports_activity: true,
+            supports_api_cache: true,
+        }
+    }
 
-        let connector = HttpsConnector::new(tls);
-        let client = Client::with_connector(connector);
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let mirrors = self.get_mirror_repos_with_meta()?;
+        Ok(mirrors.into_iter().map(|(m, _)| m).collect())
+    }
 
-        let use_http = self.use_http;
+    fn get_mirror_repos_with_meta(&self) -> Result<Vec<(MirrorResult, ProviderMeta)>, String> {
+        Ok(self.mirrors(self.list_projects()?))
+    }
 
-        let mut headers = Headers::new();
-        match self.private_token.clone() {
-            Some(token) => {
-                headers.set(PrivateToken(token));
+    fn get_mirror_repos_explained(&self) -> Result<ExplainedMirrors, String> {
+        let mut projects = Vec::new();
+        let mut filtered = Vec::new();
+        for (p, reason) in self.list_projects_filtered()? {
+            match reason {
+                Some(reason) => filtered.push(FilteredProject { web_url: p.web_url, reason }),
+                None => projects.push(p),
             }
-            None => warn!("GITLAB_PRIVATE_TOKEN not set"),
         }
+        Ok((self.mirrors(projects), filtered))
+    }
 
-        let groups = if self.recursive {
-            self.get_subgroups(&self.group, &client, &headers)?
-        } else {
-            vec![self.group.clone()]
-        };
+    fn count(&self) -> Result<ProjectCount, String> {
+        let mut count = ProjectCount::default();
+        for p in self.list_projects()? {
+            count.add(guess_description(&p.description.unwrap_or_default()));
+        }
+        Ok(count)
+    }
+}
 
-        let mut projects: Vec<Project> = Vec::new();
+impl GitLab {
+    /// The mirrors of projects with the metadata of their project
+    fn mirrors(&self, projects: Vec<Project>) -> Vec<(MirrorResult, ProviderMeta)> {
+        let use_http = self.use_http;
 
-        for group in groups {
-            projects.extend(self.get_projects(&group, &client, &headers)?);
-        }
+        let descs: Vec<Result<Desc, serde_yaml::Error>> = projects
+            .iter()
+            .map(|p| serde_yaml::from_str::<Desc>(p.description.as_ref().map_or("", |d| d)))
+            .collect();
+        // Only projects that are mirrored take a flattened name
+        let mut flattened = match self.flatten {
+            Some(ref flatten) => {
+                let paths: Vec<String> = projects
+                    .iter()
+                    .zip(&descs)
+                    .filter(|(_, d)| d.as_ref().is_ok_and(|d| !d.skip))
+                    .map(|(p, _)| if p.path_with_namespace.is_empty() {
+                        p.name.clone()
+                    } else {
+                        p.path_with_namespace.clone()
+                    })
+                    .collect();
+                flatten.destinations(&paths).into_iter()
+            }
+            None => Vec::new().into_iter(),
+        };
 
-        let mut mirrors: Vec<MirrorResult> = Vec::new();
+        let mut mirrors: Vec<(MirrorResult, ProviderMeta)> = Vec::new();
+        // Only needed to list protected branches
+        let mut client = None;
 
-        for p in projects {
-            match serde_yaml::from_str::<Desc>(&p.description) {
+        for (p, desc) in projects.into_iter().zip(descs) {
+            let meta = ProviderMeta {
+                name: Some(p.name),
+                web_url: Some(p.web_url.clone()),
+                visibility: p.visibility,
+                last_activity: p.last_activity_at,
+            };
+            let mirror = match desc {
                 Ok(desc) => {
                     if desc.skip {
-                        mirrors.push(Err(MirrorError::Skip(p.web_url)));
+                        mirrors.push((Err(MirrorError::Skip(p.web_url)), meta));
                         continue;
                     }
-                    trace!("{0} -> {1}", desc.origin, p.ssh_url_to_repo);
-                    let destination = if use_http {
+                    let destination = if let Some(d) = flattened.next() {
+                        d
+                    } else if use_http {
                         p.http_url_to_repo
                     } else {
                         p.ssh_url_to_repo
                     };
-                    let m = Mirror {
-                        origin: desc.origin,
-                        destination: destination,
-                    };
-                    mirrors.push(Ok(m));
-                }
-                Err(e) => {
-                    mirrors.push(Err(MirrorError::Description(p.web_url, e)));
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    let protected_only = desc.protected_only;
+                    let mut mirror = Mirror::from_desc(desc, destination, self.all_refs);
+                    if protected_only {
+                        let client = client.get_or_insert_with(|| self.client());
+                        let branch_map = mirror.branch_map.take();
+                        match self.protected_refspecs(p.id, &p.path_with_namespace, branch_map.as_ref(), client) {
+                            Ok(refspecs) => {
+                                if mirror.refspecs.is_some() {
+                                    warn!("Ignoring the refspecs of {}, it has protected_only", p.web_url);
+                                }
+                                // The protected branches are renamed by the refspecs
+                                mirror.refspecs = Some(refspecs);
+                            }
+                            Err(e) => {
+                                // Pushing all branches would mirror more than the description allows
+                                let e = serde_yaml::Error::custom(format!("protected_only: {}", e));
+                                mirrors.push((Err(MirrorError::Description(p.web_url, e)), meta));
+                                continue;
+                            }
+                        }
+                    }
+                    Ok(mirror)
                 }
+                Err(e) => Err(MirrorError::Description(p.web_url, e)),
+            };
+            mirrors.push((mirror, meta));
+        }
+
+        // The protected branches are listed after the listings were saved
+        if let (Some(cache), Some(_)) = (self.api_cache.as_ref(), client) {
+            if let Err(e) = cache.save() {
+                warn!("{}", e);
+            }
+        }
+
+        mirrors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::Mutex;
+    use std::thread;
+
+    use super::*;
+    use provider::EnvResolver;
+
+    /// A canned response: extra headers and the JSON body
+    type Response = (Vec<(&'static str, String)>, String);
+
+    /// An HTTP server answering requests by path and query with canned responses, others with 404
+    ///
+    /// `{url}` in the header values is replaced by the url of the server, e.g. for `Link` headers.
+    /// Responses with a `Location` header are redirects.
+    struct MockServer {
+        url: String,
+        requests: Arc<Mutex<Vec<String>>>,
+        tokens: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    impl MockServer {
+        fn start(routes: HashMap<String, Response>) -> MockServer {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let tokens = Arc::new(Mutex::new(Vec::new()));
+            let routes = Arc::new(routes);
+            let recorded = requests.clone();
+            let recorded_tokens = tokens.clone();
+            let server_url = url.clone();
+            thread::spawn(move || for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let routes = routes.clone();
+                let recorded = recorded.clone();
+                let recorded_tokens = recorded_tokens.clone();
+                let server_url = server_url.clone();
+                // Parallel fetching needs concurrent connections
+                thread::spawn(move || {
+                    let mut reader = BufReader::new(stream.try_clone().unwrap());
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    let mut line = String::new();
+                    let mut token = None;
+                    while reader.read_line(&mut line).unwrap() > 2 {
+                        if let Some((name, value)) = line.split_once(':') {
+                            if name.eq_ignore_ascii_case("private-token") {
+                                token = Some(value.trim().to_owned());
+                            }
+                        }
+                        line.clear();
+                    }
+                    let target = request_line.split(' ').nth(1).unwrap_or("").to_owned();
+                    recorded.lock().unwrap().push(target.clone());
+                    recorded_tokens.lock().unwrap().push(token);
+                    let (status, headers, body) = match routes.get(&target) {
+                        Some((headers, body)) if headers.iter().any(|&(name, _)| name == "Location") => {
+                            ("302 Found", headers.clone(), body.clone())
+                        }
+                        Some((headers, body)) => ("200 OK", headers.clone(), body.clone()),
+                        None => ("404 Not Found", Vec::new(), "{}".to_owned()),
+                    };
+                    let mut response = format!(
+                        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+                        status,
+                        body.len()
+                    );
+                    for (name, value) in headers {
+                        response.push_str(&format!("{}: {}\r\n", name, value.replace("{url}", &server_url)));
+                    }
+                    response.push_str("\r\n");
+                    response.push_str(&body);
+                    let _ = stream.write_all(response.as_bytes());
+                });
+            });
+            MockServer { url, requests, tokens }
+        }
+
+        /// The paths with query of all requests so far, in the order they arrived
+        fn requests(&self) -> Vec<String> {
+            self.requests.lock().unwrap().clone()
+        }
+
+        /// The private tokens sent with the requests, in the same order
+        fn tokens(&self) -> Vec<Option<String>> {
+            self.tokens.lock().unwrap().clone()
+        }
+    }
+
+    fn gitlab(url: &str) -> GitLab {
+        GitLab {
+            url: url.to_owned(),
+            base_path: None,
+            scope: Scope::Group("mirror".to_owned()),
+            use_http: false,
+            token: Arc::new(EnvResolver::new("GIT_MIRROR_TEST_TOKEN_NOT_SET")),
+            recursive: false,
+            fetch_mode: ProviderFetchMode::Sequential,
+            all_refs: false,
+            since: None,
+            api_cache: None,
+            max_redirects: 0,
+            flatten: None,
+            keyset_pagination: false,
+            exclude_project_ids: None,
+            exclude_paths: None,
+        }
+    }
+
+    /// A project as JSON, last_activity_at is a day of January 2020 if given
+    fn project(id: u64, day: Option<u32>) -> String {
+        format!(
+            r#"{{"id": {id}, "name": "p{id}", "web_url": "https://gitlab.example.com/mirror/p{id}",
+               "ssh_url_to_repo": "git@gitlab.example.com:mirror/p{id}.git",
+               "http_url_to_repo": "https://gitlab.example.com/mirror/p{id}.git"{activity}}}"#,
+            id = id,
+            activity = day.map(|d| format!(r#", "last_activity_at": "2020-01-{:02}T00:00:00Z""#, d))
+                .unwrap_or_default()
+        )
+    }
+
+    /// A page of projects as JSON, all with the same last activity
+    fn projects(ids: &[u64], day: Option<u32>) -> String {
+        let projects: Vec<String> = ids.iter().map(|&id| project(id, day)).collect();
+        format!("[{}]", projects.join(","))
+    }
+
+    fn ids(projects: &[Project]) -> Vec<u64> {
+        let mut ids: Vec<u64> = projects.iter().map(|p| p.id).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn base_url_has_no_trailing_slash() {
+        assert_eq!(gitlab("https://gitlab.example.com").base_url(), "https://gitlab.example.com");
+        assert_eq!(gitlab("https://gitlab.example.com//").base_url(), "https://gitlab.example.com");
+        assert_eq!(
+            gitlab("https://gitlab.example.com/").api_url("projects"),
+            "https://gitlab.example.com/api/v4/projects"
+        );
+    }
+
+    #[test]
+    fn base_url_includes_the_base_path() {
+        let mut gl = gitlab("https://example.com/");
+        for path in &["gitlab", "/gitlab", "gitlab/", "/gitlab/"] {
+            gl.base_path = Some(path.to_string());
+            assert_eq!(gl.base_url(), "https://example.com/gitlab");
+        }
+        gl.base_path = Some("/".to_owned());
+        assert_eq!(gl.base_url(), "https://example.com");
+        // A subpath in the url itself is kept
+        let mut gl = gitlab("https://example.com/tools/");
+        assert_eq!(gl.base_url(), "https://example.com/tools");
+        gl.base_path = Some("gitlab".to_owned());
+        assert_eq!(gl.api_url("user"), "https://example.com/tools/gitlab/api/v4/user");
+    }
+
+    #[test]
+    fn link_next_finds_the_next_link() {
+        assert_eq!(
+            link_next(r#"<https://gitlab.example.com/api/v4/projects?cursor=a>; rel="next""#),
+            Some("https://gitlab.example.com/api/v4/projects?cursor=a".to_owned())
+        );
+        assert_eq!(link_next("<https://host/next>;rel=next"), Some("https://host/next".to_owned()));
+    }
+
+    #[test]
+    fn link_next_without_next_link() {
+        assert_eq!(link_next(r#"<https://host/first>; rel="first", <https://host/last>; rel="last""#), None);
+        assert_eq!(link_next(r#"<https://host/page>; rel="nextish""#), None);
+        assert_eq!(link_next(r#"https://host/page; rel="next""#), None);
+        assert_eq!(link_next(""), None);
+    }
+
+    #[test]
+    fn link_next_among_other_relations() {
+        let value = r#"<https://host/first>; rel="first", <https://host/next?id_after=2>; rel="next",
+                       <https://host/last>; rel="last""#;
+        assert_eq!(link_next(value), Some("https://host/next?id_after=2".to_owned()));
+        // A link may have several parameters, in any order
+        assert_eq!(
+            link_next(r#"<https://host/next>; title="more"; rel="next""#),
+            Some("https://host/next".to_owned())
+        );
+        // And several relations
+        assert_eq!(link_next(r#"<https://host/2>; rel="next last""#), Some("https://host/2".to_owned()));
+        assert_eq!(link_next(r#"<https://host/2>; rel="prev first""#), None);
+    }
+
+    #[test]
+    fn parallel_fetching_returns_the_pages_of_sequential_fetching() {
+        let mut routes = HashMap::new();
+        for page in 1..4u64 {
+            let mut headers = vec![("X-Total-Pages", "3".to_owned())];
+            if page < 3 {
+                headers.push(("X-Next-Page", (page + 1).to_string()));
             }
+            let ids = [page * 10, page * 10 + 1];
+            routes.insert(format!("/projects?per_page=100&page={}", page), (headers, projects(&ids, None)));
         }
+        let server = MockServer::start(routes);
+        let url = format!("{}/projects", server.url);
+
+        let mut gl = gitlab(&server.url);
+        let client = gl.client();
+        let sequential = gl.get_paged::<Project>(&url, &client, &Headers::new()).unwrap();
+        gl.fetch_mode = ProviderFetchMode::Parallel { workers: 3 };
+        let parallel = gl.get_paged::<Project>(&url, &client, &Headers::new()).unwrap();
+        assert_eq!(ids(&sequential), vec![10, 11, 20, 21, 30, 31]);
+        assert_eq!(ids(&parallel), ids(&sequential));
+        assert_eq!(server.requests().len(), 6);
+    }
+
+    #[test]
+    fn parallel_fetching_of_a_single_or_no_page() {
+        let mut routes = HashMap::new();
+        routes.insert(
+            "/empty?per_page=100&page=1".to_owned(),
+            (vec![("X-Total-Pages", "0".to_owned())], "[]".to_owned()),
+        );
+        routes.insert(
+            "/single?per_page=100&page=1".to_owned(),
+            (vec![("X-Total-Pages", "1".to_owned())], projects(&[1], None)),
+        );
+        let server = MockServer::start(routes);
+        let mut gl = gitlab(&server.url);
+        gl.fetch_mode = ProviderFetchMode::Parallel { workers: 2 };
+        let client = gl.client();
+
+        let empty = gl.get_paged::<Project>(&format!("{}/empty", server.url), &client, &Headers::new());
+        assert_eq!(ids(&empty.unwrap()), Vec::<u64>::new());
+        let single = gl.get_paged::<Project>(&format!("{}/single", server.url), &client, &Headers::new());
+        assert_eq!(ids(&single.unwrap()), vec![1]);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[test]
+    fn since_stops_at_the_first_page_with_older_projects() {
+        let mut routes = HashMap::new();
+        let pages = [
+            projects(&[1, 2], Some(20)),
+            // Project 3 is still active, project 4 on the same page isn't
+            format!("[{},{}]", project(3, Some(10)), project(4, Some(4))),
+            projects(&[5, 6], Some(1)),
+        ];
+        for (i, body) in pages.iter().enumerate() {
+            let page = i + 1;
+            let mut headers = vec![("X-Total-Pages", "3".to_owned())];
+            if page < 3 {
+                headers.push(("X-Next-Page", (page + 1).to_string()));
+            }
+            routes.insert(
+                format!("/projects?order_by=last_activity_at&sort=desc&per_page=100&page={}", page),
+                (headers, body.clone()),
+            );
+        }
+        let server = MockServer::start(routes);
+        let mut gl = gitlab(&server.url);
+        gl.since = Some("2020-01-05T00:00:00Z".parse().unwrap());
+        // Listing by activity is sequential, whatever the fetch mode
+        gl.fetch_mode = ProviderFetchMode::Parallel { workers: 3 };
+
+        let listed = gl.get_project_pages(&format!("{}/projects", server.url), &gl.client(), &Headers::new());
+        // The older project of the last page is filtered later by list_projects_filtered
+        assert_eq!(ids(&listed.unwrap()), vec![1, 2, 3, 4]);
+        assert_eq!(server.requests().len(), 2);
+        assert!(server.requests().iter().all(|r| !r.ends_with("page=3")));
+    }
+
+    #[test]
+    fn keyset_pagination_follows_the_links_until_the_last_page() {
+        let first = "/projects?order_by=id&sort=asc&pagination=keyset&per_page=100".to_owned();
+        let after = |id: u64| format!("/projects?id_after={}&order_by=id&pagination=keyset&per_page=100&sort=asc", id);
+        let link = |id: u64| ("Link", format!(r#"<{{url}}{}>; rel="next""#, after(id)));
+        let mut routes = HashMap::new();
+        routes.insert(first.clone(), (vec![link(2)], projects(&[1, 2], None)));
+        routes.insert(after(2), (vec![link(4)], projects(&[3, 4], None)));
+        // The last page has no link to a next page
+        routes.insert(after(4), (Vec::new(), projects(&[5], None)));
+        let server = MockServer::start(routes);
+        let mut gl = gitlab(&server.url);
+        gl.keyset_pagination = true;
+
+        let listed = gl.get_project_pages(&format!("{}/projects", server.url), &gl.client(), &Headers::new());
+        assert_eq!(ids(&listed.unwrap()), vec![1, 2, 3, 4, 5]);
+        assert_eq!(server.requests(), vec![first, after(2), after(4)]);
+    }
+
+    #[test]
+    fn private_token_is_not_sent_to_another_port() {
+        let mut routes = HashMap::new();
+        routes.insert("/projects".to_owned(), (Vec::new(), "[]".to_owned()));
+        // Same host, another port
+        let other = MockServer::start(routes);
+        let mut routes = HashMap::new();
+        routes.insert("/old".to_owned(), (vec![("Location", "{url}/moved".to_owned())], "{}".to_owned()));
+        routes.insert("/moved".to_owned(), (vec![("Location", format!("{}/projects", other.url))], "{}".to_owned()));
+        let server = MockServer::start(routes);
+        let mut headers = Headers::new();
+        headers.set(PrivateToken("secret".to_owned()));
 
-        return Ok(mirrors);
+        let res = get(&gitlab(&server.url).client(), &format!("{}/old", server.url), headers, 2).unwrap();
+        assert_eq!(res.status, StatusCode::Ok);
+        assert_eq!(server.requests(), vec!["/old", "/moved"]);
+        assert_eq!(server.tokens(), vec![Some("secret".to_owned()), Some("secret".to_owned())]);
+        assert_eq!(other.requests(), vec!["/projects"]);
+        assert_eq!(other.tokens(), vec![None]);
     }
 }
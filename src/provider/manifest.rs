@@ -0,0 +1,159 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+// Used to parse the manifest
+extern crate serde;
+extern crate serde_yaml;
+use self::serde::de::Error;
+
+use provider::{Desc, Mirror, MirrorError, MirrorResult, Provider};
+
+/// A provider reading mirrors from a YAML manifest file in a git repository
+///
+/// The manifest is a list of mirror descriptions with an additional `destination`:
+///
+/// ``` yaml
+/// - origin: https://git.example.org/project.git
+///   destination: git@mirror.example.org:mirror/project.git
+/// - origin: https://git.example.org/other.git
+///   destination: git@mirror.example.org:mirror/other.git
+///   skip: true
+/// ```
+#[derive(Debug, Clone)]
+pub struct ManifestProvider {
+    /// Url of the repository containing the manifest
+    pub repo: String,
+    /// Path of the manifest file inside the repository
+    pub path: String,
+    /// Branch or tag the manifest is read from, the default branch of the repository if `None`
+    pub revision: Option<String>,
+    /// Directory the repository is cloned to and fetched in on later runs
+    pub cache_dir: PathBuf,
+}
+
+impl ManifestProvider {
+    /// Run a git command, returning its stdout or an error containing its stderr
+    fn git(&self, cmd: &mut Command, action: &str) -> Result<String, String> {
+        cmd.env("GIT_TERMINAL_PROMPT", "0")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        trace!("Manifest command started: {:?}", cmd);
+        let out = cmd.output().map_err(|e| {
+            format!(
+                "Unable to {} {}, make sure git is installed ({})",
+                action,
+                self.repo,
+                e
+            )
+        })?;
+        if !out.status.success() {
+            return Err(format!(
+                "Unable to {} {} ({})",
+                action,
+                self.repo,
+                String::from_utf8_lossy(&out.stderr).trim()
+            ));
+        }
+        Ok(String::from_utf8_lossy(&out.stdout).into_owned())
+    }
+
+    /// Clone the manifest repository or fetch all branches and tags of an earlier clone
+    fn update(&self) -> Result<(), String> {
+        if self.cache_dir.is_dir() {
+            self.git(
+                Command::new("git")
+                    .current_dir(&self.cache_dir)
+                    .args(["remote", "set-url", "origin"])
+                    .arg(&self.repo),
+                "set the url of the manifest repository",
+            )?;
+            self.git(
+                Command::new("git").current_dir(&self.cache_dir).args(
+                    [
+                        "fetch",
+                        "--prune",
+                        "--quiet",
+                        "origin",
+                        "+refs/heads/*:refs/heads/*",
+                        "+refs/tags/*:refs/tags/*",
+                    ],
+                ),
+                "fetch the manifest repository",
+            )?;
+        } else {
+            self.git(
+                Command::new("git")
+                    .args(["clone", "--bare", "--quiet"])
+                    .arg(&self.repo)
+                    .arg(&self.cache_dir),
+                "clone the manifest repository",
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The branch or tag the manifest is read from
+    fn revision(&self) -> &str {
+        self.revision.as_deref().unwrap_or("HEAD")
+    }
+}
+
+impl Provider for ManifestProvider {
+    fn get_label(&self) -> String {
+        format!("{}:{}", self.repo, self.path)
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        self.update()?;
+
+        let manifest = self.git(
+            Command::new("git")
+                .current_dir(&self.cache_dir)
+                .arg("show")
+                .arg(format!("{}:{}", self.revision(), self.path)),
+            &format!(
+                "read {} at {} of the manifest repository",
+                self.path,
+                self.revision()
+            ),
+        )?;
+
+        let entries: Vec<serde_yaml::Value> = serde_yaml::from_str(&manifest).map_err(|e| {
+            format!(
+                "Unable to parse the manifest {} of {}, expected a YAML list of mirrors ({})",
+                self.path,
+                self.repo,
+                e
+            )
+        })?;
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+        for (i, entry) in entries.into_iter().enumerate() {
+            // Entries are reported like projects of other providers
+            let label = format!("{} entry {}", self.get_label(), i + 1);
+            let mirror = serde_yaml::from_value::<Desc>(entry)
+                .and_then(Desc::without_protected_only)
+                .and_then(|desc| match desc.destination.clone() {
+                    Some(destination) => Ok((desc, destination)),
+                    None => Err(serde_yaml::Error::custom("missing field `destination`")),
+                });
+            mirrors.push(match mirror {
+                Ok((ref desc, _)) if desc.skip => Err(MirrorError::Skip(label)),
+                Ok((desc, destination)) => {
+                    trace!("{0} -> {1}", desc.origin, destination);
+                    Ok(Mirror::from_desc(desc, destination, true))
+                }
+                Err(e) => Err(MirrorError::Description(label, e)),
+            });
+        }
+
+        Ok(mirrors)
+    }
+}
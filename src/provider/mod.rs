@@ -4,14 +4,171 @@
  * SPDX-License-Identifier:     MIT
  */
 
+// Used to read the body of error responses
+use std::io::Read;
+use std::collections::BTreeMap;
+use std::fmt;
 
+extern crate serde;
 extern crate serde_yaml;
+use self::serde::de::Error;
 
 /// A representation of a mirror job from orgin to destination
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Mirror {
     pub origin: String,
     pub destination: String,
+    /// Additional destinations the origin is pushed to
+    pub extra_destinations: Vec<String>,
+    /// Push all refs of origin instead of only the branches and tags
+    pub all_refs: bool,
+    /// Refspecs pushed instead of the refs selected by all_refs
+    pub refspecs: Option<Vec<String>>,
+    /// Clone the origin without blobs, see `Git::fetch_repo`
+    pub partial: bool,
+    /// HTTP basic auth credentials used to fetch the origin
+    pub origin_credentials: Option<Credentials>,
+    /// Also mirror the submodules referenced by the origin, see `Mirror::submodule`
+    pub recurse_submodules: bool,
+    /// Free form labels used to select mirrors for a run, e.g. `critical` or `nightly`
+    pub labels: Vec<String>,
+    /// Git config used by all git commands of this mirror, e.g. `http.postBuffer`
+    pub git_config: Option<Vec<(String, String)>>,
+    /// Arguments added to the clone and fetch of the origin, see `ExtraArgs::for_fetch`
+    pub extra_clone_args: Option<Vec<String>>,
+    /// Arguments added to the pushes to the destinations, see `ExtraArgs::for_push`
+    pub extra_push_args: Option<Vec<String>>,
+    /// New names of branches on the destinations by their name in the origin, see `branch_map_refspecs`
+    pub branch_map: Option<BTreeMap<String, String>>,
+}
+
+/// A username and password for HTTP basic auth
+///
+/// The values are used as given, `${VAR}` isn't expanded: anyone who can edit a description could
+/// otherwise send the environment of git-mirror to an origin of their choice, see `--origin-credential`.
+/// The password is not shown in the `Debug` output.
+#[derive(Clone, PartialEq)]
+pub struct Credentials {
+    pub username: Option<String>,
+    pub password: Option<String>,
+}
+
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("username", &self.username)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .finish()
+    }
+}
+
+impl Mirror {
+    /// Create a mirror from a project description and the destination derived from the project
+    ///
+    /// all_refs is the default of the provider, the description can override it.
+    fn from_desc(desc: Desc, destination: String, all_refs: bool) -> Mirror {
+        Mirror {
+            origin: desc.origin,
+            destination,
+            extra_destinations: desc.destinations,
+            all_refs: desc.all_refs.unwrap_or(all_refs),
+            refspecs: desc.refspecs,
+            partial: desc.partial,
+            origin_credentials: match (desc.origin_username, desc.origin_password) {
+                (None, None) => None,
+                (username, password) => Some(Credentials { username, password }),
+            },
+            recurse_submodules: desc.recurse_submodules,
+            labels: desc.labels,
+            git_config: desc.git_config.map(|config| {
+                config.into_iter().map(|(k, v)| (k, v.to_string())).collect()
+            }),
+            extra_clone_args: desc.extra_clone_args,
+            extra_push_args: desc.extra_push_args,
+            branch_map: desc.branch_map,
+        }
+    }
+
+    /// Name of the mirrored repository, the last path component of the destination without `.git`
+    pub fn name(&self) -> &str {
+        repo_name(&self.destination)
+    }
+
+    /// A mirror of the submodule with url in the origin of this mirror
+    ///
+    /// Relative urls are resolved against the origin. The submodule is pushed next to the destination,
+    /// to a repository named after the submodule. The credentials, git config and extra arguments of
+    /// this mirror are not passed on, the labels are.
+    pub fn submodule(&self, url: &str) -> Mirror {
+        let origin = if url.starts_with("./") || url.starts_with("../") {
+            resolve_relative_url(&self.origin, url)
+        } else {
+            url.to_owned()
+        };
+        let parent = self.destination.trim_end_matches('/');
+        let prefix = match parent.rfind(['/', ':']) {
+            Some(i) => &parent[..i + 1],
+            None => "",
+        };
+        let destination = format!("{}{}.git", prefix, repo_name(&origin));
+        Mirror {
+            origin,
+            destination,
+            extra_destinations: Vec::new(),
+            all_refs: self.all_refs,
+            refspecs: None,
+            partial: self.partial,
+            origin_credentials: None,
+            recurse_submodules: self.recurse_submodules,
+            labels: self.labels.clone(),
+            git_config: None,
+            extra_clone_args: None,
+            extra_push_args: None,
+            branch_map: None,
+        }
+    }
+
+    /// All destinations of this mirror, starting with the derived destination
+    pub fn destinations(&self) -> impl Iterator<Item = &str> {
+        Some(self.destination.as_str()).into_iter().chain(
+            self.extra_destinations
+                .iter()
+                .map(|d| d.as_str()),
+        )
+    }
+}
+
+/// The last path component of a repository url without `.git`
+fn repo_name(url: &str) -> &str {
+    let path = url.trim_end_matches('/');
+    let name = path.rsplit(['/', ':']).next().unwrap_or(path);
+    name.trim_end_matches(".git")
+}
+
+/// Resolve a relative submodule url like `../lib.git` against the url of the superproject
+fn resolve_relative_url(base: &str, url: &str) -> String {
+    let mut base = base.trim_end_matches('/');
+    let mut url = url;
+    loop {
+        if let Some(rest) = url.strip_prefix("./") {
+            url = rest;
+        } else if let Some(rest) = url.strip_prefix("../") {
+            url = rest;
+            base = match base.rfind(['/', ':']) {
+                // Keep the colon of scp like urls, `git@host:project.git`
+                Some(i) if base[i..].starts_with(':') => &base[..i + 1],
+                Some(i) => &base[..i],
+                None => "",
+            };
+        } else {
+            break;
+        }
+    }
+    if base.is_empty() || base.ends_with(':') {
+        format!("{}{}", base, url)
+    } else {
+        format!("{}/{}", base, url)
+    }
 }
 
 /// An error occuring during mirror creation
@@ -23,21 +180,353 @@ pub enum MirrorError {
 
 pub type MirrorResult = Result<Mirror, MirrorError>;
 
+/// Mirrors with the metadata of their projects and the projects left out, see `Provider::get_mirror_repos_explained`
+pub type ExplainedMirrors = (Vec<(MirrorResult, ProviderMeta)>, Vec<FilteredProject>);
+
+/// Provider agnostic metadata of the project a mirror came from
+///
+/// Fields the provider doesn't know are `None`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProviderMeta {
+    pub name: Option<String>,
+    /// Url of the project in the web interface of the provider
+    pub web_url: Option<String>,
+    /// Visibility of the project, e.g. `private`, `internal` or `public`
+    pub visibility: Option<String>,
+    /// Time of the last activity in the project as reported by the provider (RFC 3339)
+    pub last_activity: Option<String>,
+}
+
+/// A project the provider doesn't turn into a mirror at all, e.g. because it is excluded
+#[derive(Debug, Clone, PartialEq)]
+pub struct FilteredProject {
+    /// Url of the project in the web interface of the provider
+    pub web_url: String,
+    /// Why the project is left out, e.g. `excluded by id`
+    pub reason: String,
+}
+
+/// Features a provider may support, so options depending on them aren't silently ignored
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ProviderCapabilities {
+    /// Mirrors are configured in the descriptions of projects, see `Desc`
+    pub supports_descriptions: bool,
+    /// Projects in nested groups are listed as well
+    pub supports_subgroups: bool,
+    /// Projects can be selected by their topics
+    pub supports_topics: bool,
+    /// Projects can be selected by the time of their last activity
+    pub supports_activity: bool,
+    /// Listings can be cached and requested conditionally, see `ApiCache`
+    pub supports_api_cache: bool,
+}
+
+/// What to sort the mirrors returned by a provider by
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    Origin,
+    Destination,
+    /// The repository name, see `Mirror::name`
+    Name,
+}
+
+/// Sort mirrors by key, keeping the order of equal mirrors
+///
+/// Projects that couldn't be turned into a mirror are moved to the front in their original order.
+pub fn sort_mirrors(mirrors: &mut [MirrorResult], key: SortKey) {
+    fn field(m: &MirrorResult, key: SortKey) -> Option<&str> {
+        m.as_ref().ok().map(|m| match key {
+            SortKey::Origin => m.origin.as_str(),
+            SortKey::Destination => m.destination.as_str(),
+            SortKey::Name => m.name(),
+        })
+    }
+    mirrors.sort_by(|a, b| field(a, key).cmp(&field(b, key)));
+}
+
+/// Keep only mirrors with at least one of labels
+///
+/// Projects that couldn't be turned into a mirror are kept, their labels are unknown.
+pub fn filter_labels(mirrors: Vec<MirrorResult>, labels: &[String]) -> Vec<MirrorResult> {
+    mirrors
+        .into_iter()
+        .filter(|m| match *m {
+            Ok(ref m) => m.labels.iter().any(|l| labels.contains(l)),
+            Err(_) => true,
+        })
+        .collect()
+}
+
 /// A structured description
 #[derive(Deserialize, Debug)]
 struct Desc {
     origin: String,
+    /// Only used by providers without a destination of their own, see `ManifestProvider`
+    #[serde(default)]
+    destination: Option<String>,
     #[serde(default)]
     skip: bool,
+    /// Additional destinations besides the project itself
+    #[serde(default)]
+    destinations: Vec<String>,
+    /// Overrides whether all refs or only branches and tags are pushed
+    #[serde(default)]
+    all_refs: Option<bool>,
+    /// Refspecs pushed instead of the default ones
+    #[serde(default)]
+    refspecs: Option<Vec<String>>,
+    /// Clone the origin without blobs
+    #[serde(default)]
+    partial: bool,
+    /// HTTP basic auth for the origin, used literally, secrets of the operator belong in `--origin-credential`
+    #[serde(default)]
+    origin_username: Option<String>,
+    #[serde(default)]
+    origin_password: Option<String>,
+    /// Also mirror the submodules of the origin
+    #[serde(default)]
+    recurse_submodules: bool,
+    /// Labels to select the mirror with `--label`
+    #[serde(default)]
+    labels: Vec<String>,
+    /// Git config for the commands of this mirror
+    #[serde(default)]
+    git_config: Option<BTreeMap<String, ConfigValue>>,
+    /// Arguments added to the clone and fetch of the origin
+    #[serde(default)]
+    extra_clone_args: Option<Vec<String>>,
+    /// Arguments added to the pushes
+    #[serde(default)]
+    extra_push_args: Option<Vec<String>>,
+    /// Only push the protected branches of the project and the tags, only supported by GitLab
+    #[serde(default)]
+    protected_only: bool,
+    /// Origin branches pushed under another name, e.g. `master: main`
+    #[serde(default)]
+    branch_map: Option<BTreeMap<String, String>>,
+}
+
+impl Desc {
+    /// Fail descriptions with `protected_only` for providers that don't know protected branches
+    ///
+    /// Pushing all branches instead would mirror more than the description allows.
+    fn without_protected_only(self) -> Result<Desc, serde_yaml::Error> {
+        if self.protected_only {
+            Err(serde_yaml::Error::custom("protected_only is only supported by the GitLab provider"))
+        } else {
+            Ok(self)
+        }
+    }
+}
+
+/// A scalar git config value in a description, e.g. `524288000` or `true`
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ConfigValue {
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    String(String),
+}
+
+impl fmt::Display for ConfigValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigValue::Bool(b) => write!(f, "{}", b),
+            ConfigValue::Int(i) => write!(f, "{}", i),
+            ConfigValue::Float(x) => write!(f, "{}", x),
+            ConfigValue::String(ref s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// How a provider fetches paginated API results
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ProviderFetchMode {
+    /// Request one page after the other
+    #[default]
+    Sequential,
+    /// Request pages concurrently using the given number of workers.
+    /// The order of the returned mirrors is not preserved in this mode.
+    Parallel { workers: usize },
+}
+
+/// A description template for a project mirroring origin
+///
+/// The template has the same shape as the descriptions parsed by the providers.
+pub fn description_template(origin: &str) -> Result<String, String> {
+    let mut fields = BTreeMap::new();
+    fields.insert("origin", origin);
+    let yaml = serde_yaml::to_string(&fields).map_err(|e| {
+        format!("Unable to create description ({})", e)
+    })?;
+
+    Ok(format!(
+        "{}\n\
+         # Temporarily exclude the project from syncing\n\
+         # skip: true\n\
+         # Additional destinations the origin is pushed to\n\
+         # destinations:\n\
+         #   - git@git.example.org:mirror/project.git\n\
+         # Push all refs instead of only branches and tags\n\
+         # all_refs: true\n",
+        yaml.trim_start_matches("---").trim()
+    ))
+}
+
+// Maximum number of bytes of a response body included in error messages
+const MAX_ERROR_BODY_LEN: u64 = 512;
+
+/// Read the body of an error response, truncated to `MAX_ERROR_BODY_LEN` bytes
+fn read_error_body<R: Read>(res: R) -> String {
+    let mut body = Vec::new();
+    if let Err(e) = res.take(MAX_ERROR_BODY_LEN + 1).read_to_end(&mut body) {
+        return format!("<unable to read body: {}>", e);
+    }
+    let truncated = body.len() as u64 > MAX_ERROR_BODY_LEN;
+    body.truncate(MAX_ERROR_BODY_LEN as usize);
+    let body = String::from_utf8_lossy(&body);
+    if truncated {
+        format!("{}...", body.trim())
+    } else {
+        body.trim().to_owned()
+    }
+}
+
+/// How a project would be handled by a mirror run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProjectKind {
+    Mirror,
+    Skipped,
+    /// The description isn't a valid mirror description
+    Invalid,
+}
+
+/// The number of projects of a provider, by how they would be handled
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ProjectCount {
+    pub projects: usize,
+    pub mirrors: usize,
+    pub skipped: usize,
+    pub invalid: usize,
+}
+
+impl ProjectCount {
+    pub fn add(&mut self, kind: ProjectKind) {
+        self.projects += 1;
+        match kind {
+            ProjectKind::Mirror => self.mirrors += 1,
+            ProjectKind::Skipped => self.skipped += 1,
+            ProjectKind::Invalid => self.invalid += 1,
+        }
+    }
+}
+
+/// Classify a description without parsing it, by looking for `origin` and `skip: true` lines
+///
+/// This is much cheaper than parsing but may be wrong for unusual YAML, e.g. flow mappings.
+fn guess_description(description: &str) -> ProjectKind {
+    // `origin_username:` starts with `origin` as well, the name must be followed by the colon
+    let field = |name: &str| {
+        description.lines().find_map(|l| {
+            l.strip_prefix(name)?.trim_start().strip_prefix(':').map(|v| v.trim())
+        })
+    };
+    match (field("origin"), field("skip")) {
+        (None, _) |
+        (Some(""), _) => ProjectKind::Invalid,
+        (_, Some("true")) => ProjectKind::Skipped,
+        _ => ProjectKind::Mirror,
+    }
 }
 
 pub trait Provider {
     fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String>;
     fn get_label(&self) -> String;
+
+    /// The features this provider supports, none by default
+    fn capabilities(&self) -> ProviderCapabilities {
+        ProviderCapabilities::default()
+    }
+
+    /// Like `get_mirror_repos`, with the metadata of the project each mirror came from
+    ///
+    /// Providers should override this if they know more about their projects than the mirrors contain.
+    fn get_mirror_repos_with_meta(&self) -> Result<Vec<(MirrorResult, ProviderMeta)>, String> {
+        Ok(
+            self.get_mirror_repos()?
+                .into_iter()
+                .map(|m| {
+                    let meta = match m {
+                        Ok(ref m) => ProviderMeta {
+                            name: Some(m.name().to_owned()),
+                            ..Default::default()
+                        },
+                        Err(MirrorError::Skip(ref url)) |
+                        Err(MirrorError::Description(ref url, _)) => ProviderMeta {
+                            web_url: Some(url.clone()),
+                            ..Default::default()
+                        },
+                    };
+                    (m, meta)
+                })
+                .collect(),
+        )
+    }
+
+    /// Like `get_mirror_repos_with_meta`, with the projects left out by the filters of the provider
+    ///
+    /// Providers filtering projects before turning them into mirrors should override this, see `explain`.
+    fn get_mirror_repos_explained(&self) -> Result<ExplainedMirrors, String> {
+        Ok((self.get_mirror_repos_with_meta()?, Vec::new()))
+    }
+
+    /// Count the projects of the provider without syncing them
+    ///
+    /// Providers should override this if they can count without building the mirrors.
+    fn count(&self) -> Result<ProjectCount, String> {
+        let mut count = ProjectCount::default();
+        for m in self.get_mirror_repos()? {
+            count.add(match m {
+                Ok(_) => ProjectKind::Mirror,
+                Err(MirrorError::Skip(_)) => ProjectKind::Skipped,
+                Err(MirrorError::Description(..)) => ProjectKind::Invalid,
+            });
+        }
+        Ok(count)
+    }
 }
 
+mod cache;
+pub use self::cache::{ApiCache, CachedPage};
+
+mod token;
+pub use self::token::{TokenResolver, EnvResolver, CommandResolver};
+
 mod gitlab;
-pub use self::gitlab::GitLab;
+pub use self::gitlab::{GitLab, Scope};
 
 mod github;
 pub use self::github::GitHub;
+
+mod stdin;
+pub use self::stdin::StdinProvider;
+
+mod manifest;
+pub use self::manifest::ManifestProvider;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guess_description_looks_for_the_whole_field_name() {
+        let desc = "origin_username: bot\norigin: https://git.example.com/repo.git\n";
+        assert_eq!(guess_description(desc), ProjectKind::Mirror);
+        assert_eq!(guess_description("origin_username: bot\norigin_password: x\n"), ProjectKind::Invalid);
+        assert_eq!(guess_description("skip_me: true\norigin: x\nskip: true"), ProjectKind::Skipped);
+        assert_eq!(guess_description("skipped: true\norigin: x\n"), ProjectKind::Mirror);
+        assert_eq!(guess_description("origin:\n"), ProjectKind::Invalid);
+        assert_eq!(guess_description("origin : x\n"), ProjectKind::Mirror);
+    }
+}
@@ -0,0 +1,67 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used to read the list of mirrors line by line
+use std::io::{self, BufRead};
+
+use provider::{Mirror, MirrorResult, Provider};
+
+/// A provider reading `origin destination` pairs from stdin
+#[derive(Debug)]
+pub struct StdinProvider;
+
+impl Provider for StdinProvider {
+    fn get_label(&self) -> String {
+        "stdin".to_owned()
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        let stdin = io::stdin();
+
+        let mut mirrors: Vec<MirrorResult> = Vec::new();
+
+        for (i, line) in stdin.lock().lines().enumerate() {
+            let n = i + 1;
+            let line = line.map_err(|e| {
+                format!("Unable to read line {} from stdin ({})", n, e)
+            })?;
+            let line = line.trim();
+
+            // Skip empty lines and comments
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 2 {
+                return Err(format!(
+                    "Invalid line {} on stdin, expected `<origin> <destination>`: {}",
+                    n,
+                    line
+                ));
+            }
+
+            trace!("{0} -> {1}", fields[0], fields[1]);
+            mirrors.push(Ok(Mirror {
+                origin: fields[0].to_owned(),
+                destination: fields[1].to_owned(),
+                extra_destinations: Vec::new(),
+                all_refs: true,
+                refspecs: None,
+                partial: false,
+                origin_credentials: None,
+                recurse_submodules: false,
+                labels: Vec::new(),
+                git_config: None,
+                extra_clone_args: None,
+                extra_push_args: None,
+                branch_map: None,
+            }));
+        }
+
+        Ok(mirrors)
+    }
+}
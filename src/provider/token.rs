@@ -0,0 +1,103 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::env;
+use std::fmt;
+use std::process::Command;
+
+/// Provides the token used to access the API of a provider
+///
+/// Providers call the resolver for every request instead of storing the token,
+/// so short lived tokens from a secrets manager can be used.
+pub trait TokenResolver: fmt::Debug + Send + Sync {
+    /// The current token, `None` if no token is configured
+    fn resolve(&self) -> Result<Option<String>, String>;
+
+    /// Human readable description of where the token comes from, used in error messages
+    fn describe(&self) -> String;
+}
+
+/// Read the token from an environment variable
+#[derive(Debug, Clone)]
+pub struct EnvResolver {
+    pub var: String,
+}
+
+impl EnvResolver {
+    pub fn new(var: &str) -> EnvResolver {
+        EnvResolver { var: var.to_owned() }
+    }
+}
+
+impl TokenResolver for EnvResolver {
+    fn resolve(&self) -> Result<Option<String>, String> {
+        Ok(env::var(&self.var).ok())
+    }
+
+    fn describe(&self) -> String {
+        format!("the `{}` environment variable", self.var)
+    }
+}
+
+/// Use the output of a command as token, e.g. `vault read -field=token secret/gitlab`
+///
+/// The command is run by the shell, the token is its stdout without surrounding whitespace.
+#[derive(Debug, Clone)]
+pub struct CommandResolver {
+    pub command: String,
+}
+
+impl CommandResolver {
+    pub fn new(command: &str) -> CommandResolver {
+        CommandResolver { command: command.to_owned() }
+    }
+
+    #[cfg(unix)]
+    fn shell_cmd(&self) -> Command {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&self.command);
+        cmd
+    }
+
+    #[cfg(not(unix))]
+    fn shell_cmd(&self) -> Command {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C").arg(&self.command);
+        cmd
+    }
+}
+
+impl TokenResolver for CommandResolver {
+    fn resolve(&self) -> Result<Option<String>, String> {
+        trace!("Running token command: {}", self.command);
+        let output = self.shell_cmd().output().map_err(|e| {
+            format!("Unable to execute token command `{}` ({})", self.command, e)
+        })?;
+
+        if !output.status.success() {
+            return Err(format!(
+                "Token command `{}` failed with exit code: {} ({})",
+                self.command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ));
+        }
+
+        let token = String::from_utf8(output.stdout).map_err(|_| {
+            format!("Token command `{}` returned invalid UTF-8", self.command)
+        })?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(format!("Token command `{}` returned an empty token", self.command));
+        }
+
+        Ok(Some(token.to_owned()))
+    }
+
+    fn describe(&self) -> String {
+        format!("the token command `{}`", self.command)
+    }
+}
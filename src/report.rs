@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::io::{self, Write};
+use std::time::Duration;
+
+// Used to write the JSON report
+extern crate serde_json;
+
+// Time handling
+use chrono::Local;
+
+use summary::{RunSummary, SyncStatus};
+
+/// Writes a `RunSummary` in a specific format
+pub trait ReportFormatter {
+    fn write(&self, summary: &RunSummary, out: &mut dyn Write) -> io::Result<()>;
+}
+
+/// Human readable summary line followed by the invalid project descriptions
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TextFormatter;
+
+impl ReportFormatter for TextFormatter {
+    fn write(&self, summary: &RunSummary, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "SUMMARY [{}]: {}", Local::now(), summary)?;
+        for e in &summary.provider_errors {
+            writeln!(out, "INVALID: {}", e)?;
+        }
+        Ok(())
+    }
+}
+
+/// The whole summary including all results as JSON
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormatter;
+
+impl ReportFormatter for JsonFormatter {
+    fn write(&self, summary: &RunSummary, out: &mut dyn Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, summary)?;
+        writeln!(out)
+    }
+}
+
+/// JUnit XML with one test case per origin and destination, so CI systems can show failing mirrors
+///
+/// Failed and blocked mirrors are failures, skipped mirrors and invalid descriptions are skipped test cases.
+/// Projects skipped in their description are not listed.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JUnitFormatter;
+
+impl ReportFormatter for JUnitFormatter {
+    fn write(&self, summary: &RunSummary, out: &mut dyn Write) -> io::Result<()> {
+        let suite = escape(&summary.label);
+        let failures = summary.failed + summary.blocked;
+        let skipped = summary
+            .results
+            .iter()
+            .filter(|r| matches!(r.status, SyncStatus::Skipped(_)))
+            .count() + summary.provider_errors.len();
+        let time: Duration = summary.results.iter().map(|r| r.duration).sum();
+
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#)?;
+        writeln!(out, "<testsuites>")?;
+        writeln!(
+            out,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" skipped="{}" errors="0" time="{:.3}">"#,
+            suite,
+            summary.results.len() + summary.provider_errors.len(),
+            failures,
+            skipped,
+            time.as_secs_f64()
+        )?;
+        for r in &summary.results {
+            let name = escape(&format!("{} -> {}", r.origin, r.destination));
+            write!(
+                out,
+                r#"    <testcase classname="{}" name="{}" time="{:.3}""#,
+                suite,
+                name,
+                r.duration.as_secs_f64()
+            )?;
+            match r.status {
+                SyncStatus::Ok | SyncStatus::UpToDate => writeln!(out, "/>")?,
+                SyncStatus::Skipped(ref reason) => {
+                    writeln!(out, ">")?;
+                    writeln!(out, r#"      <skipped message="{}"/>"#, escape(reason))?;
+                    writeln!(out, "    </testcase>")?;
+                }
+                SyncStatus::Failed(ref e) |
+                SyncStatus::Blocked(ref e) => {
+                    let kind = match r.status {
+                        SyncStatus::Blocked(_) => "blocked",
+                        _ => "failed",
+                    };
+                    writeln!(out, ">")?;
+                    writeln!(
+                        out,
+                        r#"      <failure type="{}" message="{}">{}</failure>"#,
+                        kind,
+                        escape(e),
+                        escape(e)
+                    )?;
+                    writeln!(out, "    </testcase>")?;
+                }
+            }
+        }
+        for e in &summary.provider_errors {
+            writeln!(
+                out,
+                r#"    <testcase classname="{}" name="{}">"#,
+                suite,
+                escape(e)
+            )?;
+            writeln!(out, r#"      <skipped message="{}"/>"#, escape(e))?;
+            writeln!(out, "    </testcase>")?;
+        }
+        writeln!(out, "  </testsuite>")?;
+        writeln!(out, "</testsuites>")
+    }
+}
+
+/// Escape text for use in XML attributes and elements
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            // Other control characters are not allowed in XML 1.0
+            '\n' | '\r' | '\t' => escaped.push(c),
+            c if c.is_control() => escaped.push(' '),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,154 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+// Used to match the urls
+extern crate regex;
+use self::regex::Regex;
+
+use provider::Mirror;
+
+/// A rule replacing the leftmost match of regex in a url with replacement
+///
+/// `$N`, `${N}` and `$name` in replacement are the text of a group, `$$` is a literal `$`.
+#[derive(Debug, Clone)]
+pub struct Rewrite {
+    pub regex: Regex,
+    pub replacement: String,
+}
+
+impl Rewrite {
+    pub fn new(pattern: &str, replacement: &str) -> Result<Rewrite, String> {
+        let regex = Regex::new(pattern).map_err(|e| format!("Invalid regex {} ({})", pattern, e))?;
+        validate_replacement(&regex, replacement)?;
+        Ok(Rewrite {
+            regex,
+            replacement: replacement.to_owned(),
+        })
+    }
+}
+
+/// Url rewrite rules applied to the mirrors after the provider resolved them, like git's `insteadOf`
+///
+/// The rules are applied in order, each to the result of the previous ones.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrites {
+    pub origin: Vec<Rewrite>,
+    /// Applied to all destinations of a mirror
+    pub destination: Vec<Rewrite>,
+}
+
+impl Rewrites {
+    /// Rewrite the origin and destinations of a mirror
+    pub fn apply(&self, mut m: Mirror) -> Mirror {
+        m.origin = rewrite_url(&m.origin, &self.origin);
+        m.destination = rewrite_url(&m.destination, &self.destination);
+        for d in &mut m.extra_destinations {
+            *d = rewrite_url(d, &self.destination);
+        }
+        m
+    }
+}
+
+/// Apply all rules in order to url
+pub fn rewrite_url(url: &str, rules: &[Rewrite]) -> String {
+    rules.iter().fold(url.to_owned(), |url, rule| {
+        if !rule.regex.is_match(&url) {
+            return url;
+        }
+        let rewritten = rule.regex.replacen(&url, 1, rule.replacement.as_str()).into_owned();
+        debug!("Rewrote {} to {} using {}", url, rewritten, rule.regex);
+        rewritten
+    })
+}
+
+/// Check that every group replacement refers to exists in regex
+///
+/// The regex crate replaces unknown groups with nothing, so a typo like `$1a` instead of `${1}a`
+/// would silently produce wrong urls.
+fn validate_replacement(regex: &Regex, replacement: &str) -> Result<(), String> {
+    let mut rest = replacement;
+    while let Some(i) = rest.find('$') {
+        let after = &rest[i + 1..];
+        if let Some(after) = after.strip_prefix('$') {
+            rest = after;
+            continue;
+        }
+        let (name, after) = if let Some(braced) = after.strip_prefix('{') {
+            let end = braced.find('}').ok_or_else(|| {
+                format!("Unterminated `${{` in replacement: {}", replacement)
+            })?;
+            (&braced[..end], &braced[end + 1..])
+        } else {
+            let end = after
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(after.len());
+            (&after[..end], &after[end..])
+        };
+        if name.is_empty() {
+            return Err(format!(
+                "Expected a group after `$` in replacement: {}, use `$$` for a literal `$`",
+                replacement
+            ));
+        }
+        let exists = match name.parse::<usize>() {
+            Ok(index) => index < regex.captures_len(),
+            Err(_) => regex.capture_names().any(|n| n == Some(name)),
+        };
+        if !exists {
+            return Err(format!(
+                "Replacement {} refers to group {} but the regex doesn't have it, use `${{1}}x` to \
+                 follow a group by letters or digits",
+                replacement,
+                name
+            ));
+        }
+        rest = after;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(rules: &[(&str, &str)]) -> Vec<Rewrite> {
+        rules.iter().map(|&(p, r)| Rewrite::new(p, r).unwrap()).collect()
+    }
+
+    #[test]
+    fn rules_are_applied_in_sequence() {
+        let rules = rules(&[
+            (r"^git@git\.internal:", "git@git.example.org:"),
+            (r"^git@([^:]+):(.*)$", "https://$1/$2"),
+            (r"\.git$", ""),
+            // Doesn't match anymore after the rule before
+            (r"^git@", "ssh://git@"),
+        ]);
+        assert_eq!(
+            rewrite_url("git@git.internal:group/project.git", &rules),
+            "https://git.example.org/group/project"
+        );
+        // Rules that don't match leave the url as is
+        assert_eq!(rewrite_url("/srv/git/project.git", &rules), "/srv/git/project");
+    }
+
+    #[test]
+    fn only_the_first_match_is_replaced() {
+        let rules = rules(&[("o", "0"), ("(?P<proto>https?)://", "${proto}s://")]);
+        assert_eq!(rewrite_url("http://foo/foo", &rules), "https://f0o/foo");
+    }
+
+    #[test]
+    fn replacements_refer_to_existing_groups() {
+        assert!(Rewrite::new("^(a)(b)$", "$2$1$$").is_ok());
+        assert!(Rewrite::new("^(?P<host>[^:]+):", "${host}/").is_ok());
+        assert!(Rewrite::new("^(a)$", "$2").is_err());
+        assert!(Rewrite::new("^(a)$", "$1a").is_err());
+        assert!(Rewrite::new("^(a)$", "${1").is_err());
+        assert!(Rewrite::new("^(a)$", "$").is_err());
+        assert!(Rewrite::new("^(a", "$1").is_err());
+    }
+}
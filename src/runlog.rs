@@ -0,0 +1,145 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use fs2::FileExt;
+
+// Used to get the hostname
+#[cfg(unix)]
+extern crate libc;
+
+// Used to serialize the records
+extern crate serde_json;
+
+use RunSummary;
+
+/// One line of the run log describing a finished run
+#[derive(Debug, Clone, Serialize)]
+pub struct RunRecord {
+    /// Start of the run as RFC 3339 time
+    pub timestamp: String,
+    pub version: String,
+    pub hostname: String,
+    /// Label of the provider the mirrors came from
+    pub provider: String,
+    pub total: usize,
+    pub success: usize,
+    pub up_to_date: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub blocked: usize,
+    /// Duration of the run in seconds
+    pub duration: f64,
+    /// Why the run itself failed, the counts are 0 then
+    pub error: Option<String>,
+}
+
+impl RunRecord {
+    pub fn new(
+        start: DateTime<Utc>,
+        duration: Duration,
+        provider: String,
+        result: &Result<RunSummary, String>,
+    ) -> RunRecord {
+        let empty = RunSummary::default();
+        let (summary, error) = match *result {
+            Ok(ref summary) => (summary, None),
+            Err(ref e) => (&empty, Some(e.clone())),
+        };
+        RunRecord {
+            timestamp: start.to_rfc3339(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            hostname: hostname(),
+            provider,
+            total: summary.total,
+            success: summary.success,
+            up_to_date: summary.up_to_date,
+            failed: summary.failed,
+            skipped: summary.skipped,
+            blocked: summary.blocked,
+            duration: duration.as_secs_f64(),
+            error,
+        }
+    }
+}
+
+/// A long lived history of runs, one JSON line per run
+///
+/// Concurrent runs are serialized with a lock on `<path>.lock`.
+/// Once appending a record would make the log larger than `max_bytes` it is moved to `<path>.1`,
+/// replacing an earlier one, and a new log is started.
+#[derive(Debug, Clone)]
+pub struct RunLog {
+    pub path: PathBuf,
+    pub max_bytes: u64,
+}
+
+impl RunLog {
+    pub fn append(&self, record: &RunRecord) -> Result<(), String> {
+        let mut line = serde_json::to_string(record).map_err(|e| format!("{}", e))?;
+        line.push('\n');
+
+        let lock_path = self.sibling(".lock");
+        let lock = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .map_err(|e| format!("Unable to open {:?} ({})", lock_path, e))?;
+        lock.lock_exclusive().map_err(|e| {
+            format!("Unable to lock {:?} ({})", lock_path, e)
+        })?;
+
+        let size = fs::metadata(&self.path).map(|m| m.len()).unwrap_or(0);
+        if size > 0 && size + line.len() as u64 > self.max_bytes {
+            let rotated = self.sibling(".1");
+            debug!("Rotating run log {:?} to {:?}", self.path, rotated);
+            fs::rename(&self.path, &rotated).map_err(|e| {
+                format!("Unable to rotate {:?} to {:?} ({})", self.path, rotated, e)
+            })?;
+        }
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .map_err(|e| format!("Unable to open {:?} ({})", self.path, e))?;
+        // The lock is released when it is dropped after writing
+        file.write_all(line.as_bytes()).map_err(|e| {
+            format!("Unable to write {:?} ({})", self.path, e)
+        })
+    }
+
+    /// path with suffix appended to its file name
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut s = self.path.clone().into_os_string();
+        s.push(suffix);
+        PathBuf::from(s)
+    }
+}
+
+/// The name of this host, `unknown` if it can't be determined
+#[cfg(unix)]
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if res != 0 {
+        return "unknown".to_owned();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8_lossy(&buf[..len]).into_owned()
+}
+
+/// The name of this host, `unknown` if it can't be determined
+#[cfg(not(unix))]
+fn hostname() -> String {
+    ::std::env::var("COMPUTERNAME").unwrap_or_else(|_| "unknown".to_owned())
+}
@@ -0,0 +1,101 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+use std::fmt;
+use std::time::Duration;
+
+// Used to serialize the summary
+extern crate serde;
+use self::serde::Serializer;
+
+/// The outcome of syncing an origin to a single destination
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum SyncStatus {
+    Ok,
+    /// Not synced because the destination already had all refs of the origin
+    UpToDate,
+    /// Skipped for the given reason
+    Skipped(String),
+    /// Failed with the given error
+    Failed(String),
+    /// Refused to sync for the given reason, e.g. because the destination host isn't allowed
+    Blocked(String),
+}
+
+/// The result of syncing an origin to a single destination
+#[derive(Debug, Clone, Serialize)]
+pub struct SyncResult {
+    pub origin: String,
+    pub destination: String,
+    pub status: SyncStatus,
+    /// Time from the start of the mirror until this destination was done
+    #[serde(serialize_with = "serialize_secs")]
+    pub duration: Duration,
+}
+
+/// A machine readable summary of a mirror run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunSummary {
+    /// Label of the provider the mirrors came from
+    pub label: String,
+    pub total: usize,
+    pub success: usize,
+    pub up_to_date: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub blocked: usize,
+    /// One result per origin and destination
+    pub results: Vec<SyncResult>,
+    /// Projects the provider wasn't able to turn into a mirror
+    pub provider_errors: Vec<String>,
+}
+
+impl RunSummary {
+    /// Add the result of a synced destination and update the counts
+    pub fn add_result(&mut self, result: SyncResult) {
+        self.total += 1;
+        match result.status {
+            SyncStatus::Ok => self.success += 1,
+            SyncStatus::UpToDate => self.up_to_date += 1,
+            SyncStatus::Skipped(_) => self.skipped += 1,
+            SyncStatus::Failed(_) => self.failed += 1,
+            SyncStatus::Blocked(_) => self.blocked += 1,
+        }
+        self.results.push(result);
+    }
+
+    /// Add a project skipped by the provider
+    pub fn add_provider_skip(&mut self) {
+        self.total += 1;
+        self.skipped += 1;
+    }
+
+    /// Add a project the provider wasn't able to turn into a mirror, it is counted as skipped
+    pub fn add_provider_error(&mut self, error: String) {
+        self.add_provider_skip();
+        self.provider_errors.push(error);
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} total, {} ok, {} up-to-date, {} failed, {} skipped, {} blocked",
+            self.label,
+            self.total,
+            self.success,
+            self.up_to_date,
+            self.failed,
+            self.skipped,
+            self.blocked
+        )
+    }
+}
+
+fn serialize_secs<S: Serializer>(d: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_f64(d.as_secs_f64())
+}
@@ -0,0 +1,419 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+//! A provider and a git backend without network access, to test whole mirror runs
+//!
+//! Only available with the `test-util` feature. `InMemoryGitBackend::do_mirror` runs a provider like
+//! `do_mirror` does, but every git operation is simulated and recorded by the backend. The mirror
+//! directory is still created, since the run locks it, nothing else is written to it.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use git::{Git, GitBackend, Push, PushError, RefChange, Refs};
+use provider::{Mirror, MirrorResult, Provider};
+use {do_mirror_with, MirrorOptions, NewBackend, OnConflict, RunSummary};
+
+/// The ref every simulated origin has
+const REF: &str = "refs/heads/master";
+
+/// A provider returning a fixed list of mirrors
+#[derive(Debug, Clone)]
+pub struct InMemoryProvider {
+    pub label: String,
+    pub mirrors: Vec<Mirror>,
+}
+
+impl Provider for InMemoryProvider {
+    fn get_label(&self) -> String {
+        self.label.clone()
+    }
+
+    fn get_mirror_repos(&self) -> Result<Vec<MirrorResult>, String> {
+        Ok(self.mirrors.iter().cloned().map(Ok).collect())
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    fetch_failures: HashMap<String, String>,
+    push_failures: HashMap<String, PushError>,
+    submodules: HashMap<String, Vec<String>>,
+    fetched: Vec<String>,
+    pushed: Vec<(String, String)>,
+    /// Origins and the destinations that have their refs
+    synced: HashSet<(String, String)>,
+}
+
+/// A git backend simulating fetches and pushes in memory
+///
+/// Every origin has a single branch, a destination has it after the origin was pushed to it.
+/// Fetches and pushes succeed unless a failure was set up with `fail_fetch`, `fail_push` or
+/// `unreachable`. Clones share their state, so a clone can be inspected after a run.
+#[derive(Debug, Clone, Default)]
+pub struct InMemoryGitBackend {
+    state: Arc<Mutex<State>>,
+}
+
+impl InMemoryGitBackend {
+    pub fn new() -> InMemoryGitBackend {
+        InMemoryGitBackend::default()
+    }
+
+    /// Let fetching origin fail with message
+    pub fn fail_fetch(&self, origin: &str, message: &str) {
+        self.state.lock().unwrap().fetch_failures.insert(origin.to_owned(), message.to_owned());
+    }
+
+    /// Let pushing to destination fail with message
+    pub fn fail_push(&self, destination: &str, message: &str) {
+        self.state.lock().unwrap().push_failures.insert(destination.to_owned(), message.to_owned().into());
+    }
+
+    /// Let pushing to destination fail like a refused connection, see `MirrorOptions::host_failure_budget`
+    pub fn unreachable(&self, destination: &str) {
+        self.state.lock().unwrap().push_failures.insert(
+            destination.to_owned(),
+            PushError {
+                message: format!("Unable to connect to {}", destination),
+                unreachable: true,
+            },
+        );
+    }
+
+    /// Let origin reference submodules with the given urls
+    pub fn submodules(&self, origin: &str, urls: &[&str]) {
+        self.state.lock().unwrap().submodules.insert(
+            origin.to_owned(),
+            urls.iter().map(|u| u.to_string()).collect(),
+        );
+    }
+
+    /// The origins fetched so far, in order
+    pub fn fetched(&self) -> Vec<String> {
+        self.state.lock().unwrap().fetched.clone()
+    }
+
+    /// The origins and destinations pushed so far, in order
+    pub fn pushed(&self) -> Vec<(String, String)> {
+        self.state.lock().unwrap().pushed.clone()
+    }
+
+    /// Run provider like `do_mirror`, with all git operations done by this backend
+    pub fn do_mirror(&self, provider: &dyn Provider, opts: &MirrorOptions) -> Result<RunSummary, String> {
+        let backend = self.clone();
+        let new_backend: NewBackend = Arc::new(move |_: Git| Box::new(backend.clone()) as Box<dyn GitBackend>);
+        do_mirror_with(provider, opts, &new_backend)
+    }
+
+    fn check_push(&self, destination: &str) -> Result<(), PushError> {
+        match self.state.lock().unwrap().push_failures.get(destination) {
+            Some(e) => Err(e.clone()),
+            None => Ok(()),
+        }
+    }
+
+    fn is_synced(&self, origin: &Path, destination: &str) -> bool {
+        self.state.lock().unwrap().synced.contains(&(
+            origin.to_string_lossy().into_owned(),
+            destination.to_owned(),
+        ))
+    }
+}
+
+/// The local directory of an origin is the origin itself, so pushes know what was fetched
+impl GitBackend for InMemoryGitBackend {
+    fn origin_dir(&self, _mirror_dir: &str, origin: &str) -> PathBuf {
+        PathBuf::from(origin)
+    }
+
+    fn origin_refs(&self, _mirror_dir: &str, origin: &str, _refs: Refs) -> Result<BTreeMap<String, String>, String> {
+        let mut refs = BTreeMap::new();
+        refs.insert(REF.to_owned(), origin.to_owned());
+        Ok(refs)
+    }
+
+    fn destination_refs(&self, destination: &str, _refs: Refs) -> Result<BTreeMap<String, String>, PushError> {
+        self.check_push(destination)?;
+        let state = self.state.lock().unwrap();
+        let mut refs = BTreeMap::new();
+        for (origin, d) in &state.synced {
+            if d == destination {
+                refs.insert(REF.to_owned(), origin.clone());
+            }
+        }
+        Ok(refs)
+    }
+
+    fn fetch_repo(&self, _mirror_dir: &str, origin: &str, _partial: bool) -> Result<PathBuf, String> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(message) = state.fetch_failures.get(origin) {
+            return Err(message.clone());
+        }
+        state.fetched.push(origin.to_owned());
+        Ok(PathBuf::from(origin))
+    }
+
+    fn submodule_urls(&self, origin_dir: &Path) -> Result<Vec<String>, String> {
+        let state = self.state.lock().unwrap();
+        Ok(state.submodules.get(&*origin_dir.to_string_lossy()).cloned().unwrap_or_default())
+    }
+
+    fn check_limits(&self, _origin_dir: &Path, _refs: Refs) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn fetch_smoke(&self, mirror_dir: &str, origin: &str) -> Result<PathBuf, String> {
+        self.fetch_repo(mirror_dir, origin, false)
+    }
+
+    fn push_smoke(&self, _smoke_dir: &Path, destination: &str) -> Result<Push, PushError> {
+        self.check_push(destination)?;
+        Ok(Push::Done)
+    }
+
+    fn remove_smoke(&self, _smoke_dir: &Path) {}
+
+    fn check_partial_support(&self, _destination: &str) {}
+
+    fn maintain(&self, _origin_dir: &Path) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn ref_changes(&self, origin_dir: &Path, destination: &str, _refs: Refs) -> Result<Vec<RefChange>, PushError> {
+        self.check_push(destination)?;
+        if self.is_synced(origin_dir, destination) {
+            Ok(Vec::new())
+        } else {
+            Ok(vec![RefChange::Create(REF.to_owned())])
+        }
+    }
+
+    fn push_repo(
+        &self,
+        origin_dir: &Path,
+        destination: &str,
+        _refs: Refs,
+        _on_conflict: OnConflict,
+    ) -> Result<Push, PushError> {
+        self.check_push(destination)?;
+        let origin = origin_dir.to_string_lossy().into_owned();
+        let mut state = self.state.lock().unwrap();
+        state.pushed.push((origin.clone(), destination.to_owned()));
+        state.synced.retain(|(_, d)| d != destination);
+        state.synced.insert((origin, destination.to_owned()));
+        Ok(Push::Done)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process;
+    use std::time::Duration;
+
+    use super::*;
+    use {FailureCache, Limits, NamingScheme, Rewrites, SyncStatus};
+
+    /// An empty mirror directory of its own for every test
+    fn mirror_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("git-mirror-testutil-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn options(dir: &Path) -> MirrorOptions {
+        MirrorOptions {
+            mirror_dir: dir.to_string_lossy().into_owned(),
+            naming_scheme: NamingScheme::default(),
+            dry_run: false,
+            dry_run_refs: false,
+            // A single worker keeps the order of fetches and pushes
+            worker_count: 1,
+            metrics_file: None,
+            sort: None,
+            timeout: None,
+            on_conflict: OnConflict::default(),
+            isolate_credentials: false,
+            unshallow: false,
+            limits: Limits::default(),
+            keep_locks: false,
+            smoke: false,
+            allowed_destination_hosts: None,
+            host_failure_budget: None,
+            submodule_depth: 0,
+            changed_only: false,
+            labels: None,
+            rewrites: Rewrites::default(),
+            failure_cache: None,
+            retry_failed: false,
+            warm_up: false,
+            verify: false,
+            gc_threshold: None,
+            freshness_probe: false,
+            adaptive_concurrency: false,
+            origin_credentials: HashMap::new(),
+        }
+    }
+
+    fn mirror(name: &str, labels: &[&str]) -> Mirror {
+        Mirror {
+            origin: format!("https://origin.example.com/{}.git", name),
+            destination: format!("git@mirror.example.com:mirror/{}.git", name),
+            extra_destinations: Vec::new(),
+            all_refs: false,
+            refspecs: None,
+            partial: false,
+            origin_credentials: None,
+            recurse_submodules: false,
+            labels: labels.iter().map(|l| l.to_string()).collect(),
+            git_config: None,
+            extra_clone_args: None,
+            extra_push_args: None,
+            branch_map: None,
+        }
+    }
+
+    fn provider(mirrors: Vec<Mirror>) -> InMemoryProvider {
+        InMemoryProvider {
+            label: "test".to_owned(),
+            mirrors,
+        }
+    }
+
+    fn origin(name: &str) -> String {
+        mirror(name, &[]).origin
+    }
+
+    fn destination(name: &str) -> String {
+        mirror(name, &[]).destination
+    }
+
+    #[test]
+    fn failures_are_counted_per_destination() {
+        let dir = mirror_dir("failures");
+        let git = InMemoryGitBackend::new();
+        git.fail_fetch(&origin("fetch"), "Repository not found");
+        git.fail_push(&destination("push"), "Permission denied");
+        let p = provider(vec![mirror("ok", &[]), mirror("fetch", &[]), mirror("push", &[])]);
+
+        let summary = git.do_mirror(&p, &options(&dir)).unwrap();
+        assert_eq!((summary.total, summary.success, summary.failed), (3, 1, 2));
+        assert_eq!(git.fetched(), vec![origin("ok"), origin("push")]);
+        assert_eq!(git.pushed(), vec![(origin("ok"), destination("ok"))]);
+        let failed: Vec<&str> = summary.results.iter().filter_map(|r| match r.status {
+            SyncStatus::Failed(ref e) => Some(e.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(failed, vec!["Repository not found", "Permission denied"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recent_failures_are_skipped_until_retried() {
+        let dir = mirror_dir("retry");
+        let cache = dir.join("failures.json");
+        let p = provider(vec![mirror("ok", &[]), mirror("flaky", &[])]);
+        let run = |git: &InMemoryGitBackend, retry_failed: bool| {
+            let mut opts = options(&dir);
+            opts.failure_cache = Some(FailureCache::load(cache.clone(), Duration::from_secs(3600)));
+            opts.retry_failed = retry_failed;
+            git.do_mirror(&p, &opts).unwrap()
+        };
+
+        let failing = InMemoryGitBackend::new();
+        failing.fail_fetch(&origin("flaky"), "Connection reset");
+        let summary = run(&failing, false);
+        assert_eq!((summary.success, summary.failed, summary.skipped), (1, 1, 0));
+
+        // The failure is remembered, the repaired origin isn't tried again
+        let repaired = InMemoryGitBackend::new();
+        let summary = run(&repaired, false);
+        assert_eq!((summary.success, summary.failed, summary.skipped), (1, 0, 1));
+        assert_eq!(repaired.fetched(), vec![origin("ok")]);
+
+        let summary = run(&repaired, true);
+        assert_eq!((summary.success, summary.failed, summary.skipped), (2, 0, 0));
+
+        // A successful sync removes the failure from the cache
+        let summary = run(&InMemoryGitBackend::new(), false);
+        assert_eq!((summary.success, summary.failed, summary.skipped), (2, 0, 0));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dry_run_neither_fetches_nor_pushes() {
+        let dir = mirror_dir("dry-run");
+        let git = InMemoryGitBackend::new();
+        let p = provider(vec![mirror("a", &[]), mirror("b", &[])]);
+        let mut opts = options(&dir);
+        opts.dry_run = true;
+
+        let summary = git.do_mirror(&p, &opts).unwrap();
+        assert_eq!((summary.total, summary.success), (2, 2));
+        assert!(git.fetched().is_empty());
+        assert!(git.pushed().is_empty());
+
+        // With the ref changes the origins are fetched, but still not pushed
+        opts.dry_run_refs = true;
+        let summary = git.do_mirror(&p, &opts).unwrap();
+        assert_eq!((summary.total, summary.success), (2, 2));
+        assert_eq!(git.fetched(), vec![origin("a"), origin("b")]);
+        assert!(git.pushed().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn only_mirrors_with_a_selected_label_are_synced() {
+        let dir = mirror_dir("labels");
+        let git = InMemoryGitBackend::new();
+        let p = provider(vec![
+            mirror("nightly", &["nightly"]),
+            mirror("critical", &["critical", "nightly"]),
+            mirror("unlabeled", &[]),
+        ]);
+        let mut opts = options(&dir);
+        opts.labels = Some(vec!["critical".to_owned()]);
+
+        let summary = git.do_mirror(&p, &opts).unwrap();
+        assert_eq!((summary.total, summary.success), (1, 1));
+        assert_eq!(git.pushed(), vec![(origin("critical"), destination("critical"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn counts_decide_the_exit_code() {
+        let dir = mirror_dir("counts");
+        let git = InMemoryGitBackend::new();
+        let mut blocked = mirror("blocked", &[]);
+        blocked.destination = "git@elsewhere.example.com:mirror/blocked.git".to_owned();
+        let p = provider(vec![mirror("ok", &[]), blocked, mirror("down", &[])]);
+        git.unreachable(&destination("down"));
+        let mut opts = options(&dir);
+        opts.allowed_destination_hosts = Some(vec!["mirror.example.com".to_owned()]);
+
+        // Failed and blocked destinations make git-mirror exit with 1, see main
+        let summary = git.do_mirror(&p, &opts).unwrap();
+        assert_eq!(
+            (summary.total, summary.success, summary.up_to_date, summary.failed, summary.blocked),
+            (3, 1, 0, 1, 1)
+        );
+
+        // Up to date destinations count like synced ones
+        opts.changed_only = true;
+        let summary = git.do_mirror(&p, &opts).unwrap();
+        assert_eq!(
+            (summary.total, summary.success, summary.up_to_date, summary.failed, summary.blocked),
+            (3, 0, 1, 1, 1)
+        );
+        assert_eq!(git.pushed(), vec![(origin("ok"), destination("ok"))]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,63 @@
+/*
+ * Copyright (c) 2017 Pascal Bach
+ *
+ * SPDX-License-Identifier:     MIT
+ */
+
+/// Extract the lowercase host of a git url
+///
+/// Supports urls like `https://host/path`, `ssh://user@host:port/path` and
+/// the scp like syntax `user@host:path`. Local paths don't have a host.
+pub fn host(url: &str) -> Option<String> {
+    let host = match url.find("://") {
+        Some(i) => {
+            let authority = url[i + 3..].split('/').next().unwrap_or("");
+            let host_port = authority.rsplit('@').next().unwrap_or("");
+            if let Some(stripped) = host_port.strip_prefix('[') {
+                // IPv6 address like [::1]:22
+                stripped.split(']').next().unwrap_or("")
+            } else {
+                host_port.split(':').next().unwrap_or("")
+            }
+        }
+        None => {
+            // The scp like syntax requires a colon before the first slash
+            let colon = url.find(':')?;
+            if url[..colon].contains('/') {
+                return None;
+            }
+            let host = url[..colon].rsplit('@').next().unwrap_or("");
+            // Windows paths like C:\repo are no hosts
+            if host.len() < 2 {
+                return None;
+            }
+            host
+        }
+    };
+
+    if host.is_empty() {
+        None
+    } else {
+        Some(host.to_lowercase())
+    }
+}
+
+/// Extract the path of a git url without leading and trailing slashes
+///
+/// Supports the same urls as `host`, for local paths the whole path is returned.
+pub fn path(url: &str) -> String {
+    let path = match url.find("://") {
+        Some(i) => {
+            let rest = &url[i + 3..];
+            rest.find('/').map(|i| &rest[i..]).unwrap_or("")
+        }
+        None => {
+            match host(url) {
+                // scp like syntax
+                Some(_) => &url[url.find(':').unwrap_or(0) + 1..],
+                None => url,
+            }
+        }
+    };
+    path.trim_matches(|c| c == '/' || c == '\\').to_owned()
+}